@@ -7,18 +7,30 @@
 use std::collections::hash_map::DefaultHasher;
 use std::env;
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs;
 use std::hash::{Hash, Hasher};
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
+use fs4::FileExt;
+use log::{debug, info, warn};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 /// The name of the profile used for buliding the sysroot.
 const DEFAULT_SYSROOT_PROFILE: &str = "custom_sysroot";
 
+/// The file extensions of the libraries that can be part of a sysroot.
+const LIBRARY_EXTENSIONS: &[&str] = &["rlib", "rmeta", "so", "dylib", "dll"];
+
+/// The prefix of the default value injected into the crate hash of all sysroot crates.
+const DEFAULT_LIB_METADATA: &str = "rustc-build-sysroot";
+
 fn rustc_sysroot_dir(mut rustc: Command) -> Result<PathBuf> {
     let output = rustc
         .args(["--print", "sysroot"])
@@ -57,6 +69,261 @@ pub fn rustc_sysroot_src(rustc: Command) -> Result<PathBuf> {
     Ok(rustc_src)
 }
 
+/// Makes `dest` a copy of the sysroot `base_sysroot` (e.g. the one of a rustup toolchain, see
+/// `rustc --print sysroot`), so that it can be used as a replacement for the entire toolchain
+/// after building a custom standard library into it with [`SysrootBuilder::build_from_source`].
+///
+/// Files are hard-linked where possible, and copied otherwise. Building a sysroot into `dest`
+/// never modifies these files in-place, so `base_sysroot` is not affected. Running this again
+/// updates `dest` to the current contents of `base_sysroot`.
+pub fn create_shadow_sysroot(base_sysroot: &Path, dest: &Path) -> Result<()> {
+    if !base_sysroot.is_dir() {
+        bail!(
+            "base sysroot `{}` is not a directory",
+            base_sysroot.display()
+        );
+    }
+    mirror_dir(base_sysroot, dest, |_| false).context("failed to create shadow sysroot")
+}
+
+/// Locates the `library` source folder (the one that contains `std/Cargo.toml`) relative to
+/// `src_dir`. `src_dir` can be the `library` folder itself, a checkout of the rust repository (or
+/// the `rust` folder of the rust-src component), or a sysroot with the rust-src component
+/// installed.
+fn find_library_dir(src_dir: &Path) -> Result<PathBuf> {
+    let candidates = [
+        src_dir.to_owned(),
+        src_dir.join("library"),
+        src_dir
+            .join("lib")
+            .join("rustlib")
+            .join("src")
+            .join("rust")
+            .join("library"),
+    ];
+    for candidate in candidates {
+        if candidate.join("std").join("Cargo.toml").exists() {
+            // Make sure the same sources always end up with the same path (which is part of the
+            // sysroot hash), no matter how it was spelled by the caller.
+            return candidate.canonicalize().with_context(|| {
+                format!("failed to canonicalize source dir {}", candidate.display())
+            });
+        }
+    }
+    Err(BuildError::SourceNotFound {
+        path: src_dir.to_owned(),
+    }
+    .into())
+}
+
+/// Returns the lock file that belongs to the library sources in `src_dir`, if there is one.
+fn find_lock_file(src_dir: &Path) -> Option<PathBuf> {
+    // Since <https://github.com/rust-lang/rust/pull/128534>, the lock file
+    // lives inside the src_dir.
+    let new_lock_file_name = src_dir.join("Cargo.lock");
+    if new_lock_file_name.exists() {
+        Some(new_lock_file_name)
+    } else {
+        // Previously, the lock file lived one folder up. (`src_dir` is canonical, so it only
+        // lacks a parent if it is the root directory, in which case there is no such lock file.)
+        let old_lock_file_name = src_dir.parent()?.join("Cargo.lock");
+        // Some source distributions do not ship a lock file at all.
+        old_lock_file_name.exists().then_some(old_lock_file_name)
+    }
+}
+
+/// The `[features]` table of one of the crates in the library sources.
+#[derive(Debug, Default)]
+struct CrateFeatures {
+    /// The names of all the features the crate declares.
+    names: Vec<String>,
+    /// The features that are enabled by default.
+    default: Vec<String>,
+}
+
+/// Reads the features of `krate` from its manifest in `src_dir`. A crate without a manifest has no
+/// features; reporting missing crates is up to [`SysrootBuilder::check_src_dir`].
+///
+/// This is not a full TOML parser, it only understands the (simple) way the standard library
+/// manifests declare their features.
+fn crate_features(src_dir: &Path, krate: &str) -> CrateFeatures {
+    let mut features = CrateFeatures::default();
+    let Ok(manifest) = fs::read_to_string(src_dir.join(krate).join("Cargo.toml")) else {
+        return features;
+    };
+    let mut add = |name: String, value: &str| {
+        if name == "default" {
+            // The quoted strings are at the odd positions when splitting at the quotes.
+            features.default = value
+                .split('"')
+                .skip(1)
+                .step_by(2)
+                .map(String::from)
+                .collect();
+        }
+        features.names.push(name);
+    };
+    let mut in_features = false;
+    // A feature whose list spans multiple lines, and the part of that list we have seen so far.
+    let mut pending: Option<(String, String)> = None;
+    for line in manifest.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some((name, mut value)) = pending.take() {
+            value.push_str(line);
+            if line.contains(']') {
+                add(name, &value);
+            } else {
+                pending = Some((name, value));
+            }
+        } else if line.starts_with('[') {
+            in_features = line == "[features]";
+        } else if let Some((name, value)) = line.split_once('=').filter(|_| in_features) {
+            let name = name.trim().trim_matches('"').to_owned();
+            if value.contains('[') && !value.contains(']') {
+                pending = Some((name, value.to_owned()));
+            } else {
+                add(name, value);
+            }
+        }
+    }
+    features
+}
+
+/// Specific ways in which building a sysroot can fail.
+///
+/// The errors returned by this crate are [`anyhow::Error`]s; use
+/// [`downcast_ref::<BuildError>`](anyhow::Error::downcast_ref) to check whether an error is one of
+/// these.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// The given source directory does not contain the standard library sources.
+    #[error(
+        "{path:?} does not seem to be a rust library source folder: `std/Cargo.toml` not found"
+    )]
+    SourceNotFound {
+        /// The source directory that was passed in.
+        path: PathBuf,
+    },
+    /// The cargo invocation that builds the sysroot failed.
+    #[error(
+        "sysroot build failed ({status}); command:\n{command}{}",
+        fmt_stderr(stderr)
+    )]
+    CargoFailed {
+        /// The cargo invocation, rendered as a shell command (including the environment variables
+        /// that were set for it) that can be used to reproduce the failure.
+        command: String,
+        /// The exit status of cargo.
+        status: ExitStatus,
+        /// Everything cargo printed to stderr.
+        stderr: String,
+    },
+    /// The cargo invocation that builds the sysroot took longer than the configured
+    /// [`SysrootBuilder::timeout`], and was killed.
+    #[error("sysroot build timed out after {timeout:?}")]
+    Timeout {
+        /// The timeout that was exceeded.
+        timeout: Duration,
+    },
+    /// The target is neither known to rustc nor an existing target specification file.
+    #[error("unknown target {target:?}")]
+    UnknownTarget {
+        /// The target that was requested.
+        target: OsString,
+    },
+    /// The freshly built sysroot could not be installed into the sysroot directory.
+    #[error("failed installing sysroot")]
+    InstallFailed,
+    /// A sysroot with `std` was requested for a target that does not support `std`.
+    #[error(
+        "target {target:?} does not support `std`; use `SysrootConfig::NoStd` to build a sysroot \
+        with only `core` and `alloc`"
+    )]
+    StdNotSupported {
+        /// The target that was requested.
+        target: OsString,
+    },
+    /// The source directory is missing a crate that is needed for the requested sysroot.
+    #[error("incomplete rust library sources: {path:?} not found")]
+    IncompleteSource {
+        /// The crate manifest that does not exist.
+        path: PathBuf,
+    },
+    /// Cargo should use the lockfile of the sources as-is ([`SysrootBuilder::locked`] or
+    /// [`SysrootBuilder::frozen`]), but the sources do not have one.
+    #[error("{src_dir:?} has no lockfile, which is required for `--locked` and `--frozen`")]
+    MissingLockfile {
+        /// The library source directory.
+        src_dir: PathBuf,
+    },
+    /// A setting of the [`SysrootBuilder`] has a value that cannot work.
+    #[error("invalid `{setting}`: {reason}")]
+    InvalidSetting {
+        /// The builder method that was given the value.
+        setting: &'static str,
+        /// What is wrong with the value.
+        reason: String,
+    },
+}
+
+fn fmt_stderr(stderr: &str) -> String {
+    if stderr.is_empty() {
+        String::new()
+    } else {
+        format!("; stderr:\n{stderr}")
+    }
+}
+
+/// Quotes the given string for a shell (bash or similar), if needed.
+fn shell_quote(s: &OsStr) -> String {
+    let s = s.to_string_lossy();
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        s.into_owned()
+    } else if s.chars().any(char::is_control) {
+        // Use ANSI-C quoting, so that e.g. the separators in `CARGO_ENCODED_RUSTFLAGS` survive
+        // copy-pasting.
+        let mut quoted = String::from("$'");
+        for c in s.chars() {
+            match c {
+                '\\' | '\'' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('\'');
+        quoted
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+/// Renders the given command as a shell command, including its working directory and the
+/// environment variables that were set or removed for it.
+fn render_command(cmd: &Command) -> String {
+    let mut parts = vec![];
+    if let Some(dir) = cmd.get_current_dir() {
+        parts.push(format!("cd {} &&", shell_quote(dir.as_os_str())));
+    }
+    let envs: Vec<_> = cmd.get_envs().collect();
+    if envs.iter().any(|(_, value)| value.is_none()) {
+        parts.push("env".to_owned());
+    }
+    for (key, value) in envs {
+        match value {
+            Some(value) => parts.push(format!("{}={}", key.to_string_lossy(), shell_quote(value))),
+            None => parts.push(format!("-u {}", key.to_string_lossy())),
+        }
+    }
+    parts.push(shell_quote(cmd.get_program()));
+    parts.extend(cmd.get_args().map(shell_quote));
+    parts.join(" ")
+}
+
 /// Encode a list of rustflags for use in CARGO_ENCODED_RUSTFLAGS.
 pub fn encode_rustflags(flags: &[OsString]) -> OsString {
     let mut res = OsString::new();
@@ -97,13 +364,17 @@ fn make_writeable(p: &Path) -> Result<()> {
     Ok(())
 }
 
-/// Hash the metadata and size of every file in a directory, recursively.
+/// Hash the relative path, metadata and size of every file in a directory, recursively.
 fn hash_recursive(path: &Path, hasher: &mut DefaultHasher) -> Result<()> {
     // We sort the entries to ensure a stable hash.
     for entry in WalkDir::new(path)
         .follow_links(true)
         .sort_by_file_name()
         .into_iter()
+        // Skip the build output directory of people who built the library in-tree; it is not an
+        // input to the sysroot build and would make the hash change on every build. (Nested
+        // `target` folders can be regular source modules, so we only skip the top-level one.)
+        .filter_entry(|e| !(e.depth() == 1 && e.file_type().is_dir() && e.file_name() == "target"))
     {
         let entry = entry?;
         // WalkDir yields the directories as well, and File::open will succeed on them. The
@@ -111,6 +382,8 @@ fn hash_recursive(path: &Path, hasher: &mut DefaultHasher) -> Result<()> {
         if entry.file_type().is_dir() {
             continue;
         }
+        // Hash the path so that renaming or moving files is also detected.
+        entry.path().strip_prefix(path).unwrap().hash(hasher);
         let meta = entry.metadata()?;
         // Hashing the mtime and file size should catch basically all mutations,
         // and is faster than hashing the file contents.
@@ -120,8 +393,377 @@ fn hash_recursive(path: &Path, hasher: &mut DefaultHasher) -> Result<()> {
     Ok(())
 }
 
+/// Returns a copy of the given command, preserving its program, arguments, environment variables
+/// and working directory.
+fn clone_command(cmd: &Command) -> Command {
+    let mut new_cmd = Command::new(cmd.get_program());
+    new_cmd.args(cmd.get_args());
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(value) => new_cmd.env(key, value),
+            None => new_cmd.env_remove(key),
+        };
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        new_cmd.current_dir(dir);
+    }
+    new_cmd
+}
+
+/// Copy the given files into `dest_dir`. To speed this up on slow file systems, the files are
+/// copied by several threads in parallel.
+///
+/// If `hardlink` is set, we try to create hard links instead of copies, and only copy files where
+/// that fails (e.g. because `dest_dir` is on another file system).
+fn copy_files(files: &[PathBuf], dest_dir: &Path, hardlink: bool) -> Result<()> {
+    let num_threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = files.len().div_ceil(num_threads).max(1);
+    thread::scope(|scope| {
+        let workers: Vec<_> = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || -> Result<()> {
+                    for file in chunk {
+                        let dest = dest_dir.join(file.file_name().unwrap());
+                        if hardlink && fs::hard_link(file, &dest).is_ok() {
+                            continue;
+                        }
+                        fs::copy(file, &dest)
+                            .with_context(|| format!("failed to copy {}", file.display()))?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })
+}
+
+/// Copy a directory and all its contents, recursively.
+fn copy_dir_recursive(from: &Path, to: &Path) -> Result<()> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let dest = to.join(entry.path().strip_prefix(from).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest)
+                .with_context(|| format!("failed to create directory {}", dest.display()))?;
+        } else {
+            fs::copy(entry.path(), &dest)
+                .with_context(|| format!("failed to copy {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Recreate the contents of the directory `from` in `to`, using hard links where possible (and
+/// copies where that fails). Symlinks are recreated as symlinks. Existing files in `to` are
+/// replaced; other existing contents of `to` are left alone. Everything for which `skip` returns
+/// `true` (given the path relative to `from`) is left out.
+fn mirror_dir(from: &Path, to: &Path, skip: impl Fn(&Path) -> bool) -> Result<()> {
+    let entries = WalkDir::new(from)
+        .into_iter()
+        .filter_entry(|entry| !skip(entry.path().strip_prefix(from).unwrap()));
+    for entry in entries {
+        let entry = entry?;
+        let dest = to.join(entry.path().strip_prefix(from).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest)
+                .with_context(|| format!("failed to create directory {}", dest.display()))?;
+            continue;
+        }
+        if dest.symlink_metadata().is_ok() {
+            fs::remove_file(&dest)
+                .with_context(|| format!("failed to remove {}", dest.display()))?;
+        }
+        #[cfg(unix)]
+        if entry.file_type().is_symlink() {
+            let link = fs::read_link(entry.path())
+                .with_context(|| format!("failed to read symlink {}", entry.path().display()))?;
+            std::os::unix::fs::symlink(link, &dest)
+                .with_context(|| format!("failed to create symlink {}", dest.display()))?;
+            continue;
+        }
+        if fs::hard_link(entry.path(), &dest).is_err() {
+            fs::copy(entry.path(), &dest)
+                .with_context(|| format!("failed to copy {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Move a directory to `to`, which must not exist yet.
+///
+/// If renaming fails (e.g. because `from` and `to` are on different filesystems), the directory is
+/// first copied into a sibling of `to` and then renamed from there, so the final step is still
+/// atomic.
+fn move_dir(from: &Path, to: &Path) -> Result<()> {
+    let rename_err = match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    let tmp_dir = TempDir::new_in(to.parent().unwrap()).with_context(|| {
+        format!("failed to move directory ({rename_err}); failed to create temporary dir")
+    })?;
+    copy_dir_recursive(from, tmp_dir.path()).with_context(|| {
+        format!("failed to move directory ({rename_err}); fallback copy failed")
+    })?;
+    fs::rename(tmp_dir.path(), to).context("failed to rename copied directory")?;
+    Ok(())
+}
+
+/// Moves a file to `to`, replacing the file that might already be there, atomically. Like
+/// [`move_dir`], this falls back to copying if renaming fails.
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    let rename_err = match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    let tmp_file = tempfile::NamedTempFile::new_in(to.parent().unwrap()).with_context(|| {
+        format!("failed to move file ({rename_err}); failed to create temporary file")
+    })?;
+    fs::copy(from, tmp_file.path())
+        .with_context(|| format!("failed to move file ({rename_err}); fallback copy failed"))?;
+    tmp_file
+        .persist(to)
+        .context("failed to rename copied file")?;
+    Ok(())
+}
+
+/// Starts the sysroot hash with the sources in `src_dir`. This is the part of the hash that takes
+/// a while, since it looks at the entire source tree.
+fn hash_sources(src_dir: &Path) -> Result<DefaultHasher> {
+    let mut hasher = DefaultHasher::new();
+    src_dir.hash(&mut hasher);
+    hash_recursive(src_dir, &mut hasher)?;
+    Ok(hasher)
+}
+
+/// Makes `path` absolute by resolving it relative to the current directory. We compare paths
+/// derived from it with the (absolute) paths that cargo reports.
+fn absolute_path(path: &Path) -> PathBuf {
+    env::current_dir().map_or_else(|_| path.to_owned(), |cwd| cwd.join(path))
+}
+
+/// Returns whether the two files have the same contents. `b` might not exist.
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let Ok(b_meta) = fs::metadata(b) else {
+        return Ok(false);
+    };
+    if !b_meta.is_file() || fs::metadata(a)?.len() != b_meta.len() {
+        return Ok(false);
+    }
+    Ok(fs::read(a)? == fs::read(b)?)
+}
+
+/// Like [`output_with_timeout`], but using tokio.
+#[cfg(feature = "tokio")]
+async fn output_with_timeout_async(
+    cmd: &Command,
+    timeout: Option<Duration>,
+) -> Result<Option<Output>> {
+    let mut cmd = tokio::process::Command::from(clone_command(cmd));
+    // Make sure cargo does not keep running when we give up on it.
+    cmd.stdin(Stdio::null()).kill_on_drop(true);
+    let Some(timeout) = timeout else {
+        return Ok(Some(cmd.output().await?));
+    };
+    match tokio::time::timeout(timeout, cmd.output()).await {
+        Ok(output) => Ok(Some(output?)),
+        Err(_elapsed) => Ok(None),
+    }
+}
+
+/// Creates the sysroot dir `dir` (if needed) and takes an exclusive lock on it. The lock is held
+/// until the returned file is dropped.
+fn lock_dir(dir: &Path) -> Result<fs::File> {
+    fs::create_dir_all(dir).context("failed to create sysroot dir")?;
+    let sysroot_lock =
+        fs::File::create(dir.join(LOCK_FILE_NAME)).context("failed to create sysroot lock file")?;
+    // We call the trait method explicitly since newer versions of std have an inherent
+    // `File::lock`, but we want to support older versions of Rust as well.
+    FileExt::lock(&sysroot_lock).context("failed to lock sysroot dir")?;
+    Ok(sysroot_lock)
+}
+
+/// Updates the sysroot in `target_dir` to match the one in `staging_dir`, only replacing the files
+/// that changed. The hash file is removed first and put in place last, so that an interrupted
+/// installation is not mistaken for a complete one (if `staging_dir` has no hash file, there is no
+/// hash file afterwards). The files in `staging_dir` are moved, not copied. The files listed in
+/// `unchanged` (relative to `target_dir`) are part of the new sysroot as well, even though they
+/// were not staged.
+fn install_incrementally(
+    staging_dir: &Path,
+    target_dir: &Path,
+    hash_file_path: &Path,
+    unchanged: &[PathBuf],
+) -> Result<()> {
+    fs::create_dir_all(target_dir).context("failed to create target directory")?;
+    let hash_file = target_dir.join(hash_file_path);
+    if hash_file.exists() {
+        fs::remove_file(&hash_file).context("failed to remove old hash file")?;
+    }
+    // Remove everything that is not part of the new sysroot. (Contents first, so that directories
+    // are empty by the time we get to them.)
+    for entry in WalkDir::new(target_dir).min_depth(1).contents_first(true) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(target_dir).unwrap();
+        if staging_dir.join(relative).exists() || unchanged.iter().any(|file| file == relative) {
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            fs::remove_dir(entry.path())
+        } else {
+            fs::remove_file(entry.path())
+        }
+        .with_context(|| format!("failed to remove {}", entry.path().display()))?;
+    }
+    // Move over everything that changed. Renaming replaces the old file atomically.
+    for entry in WalkDir::new(staging_dir).min_depth(1) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(staging_dir).unwrap();
+        let dest = target_dir.join(relative);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest)
+                .with_context(|| format!("failed to create {}", dest.display()))?;
+        } else if relative != hash_file_path && !same_contents(entry.path(), &dest)? {
+            move_file(entry.path(), &dest)
+                .with_context(|| format!("failed to install {}", dest.display()))?;
+        }
+    }
+    let staged_hash_file = staging_dir.join(hash_file_path);
+    if staged_hash_file.exists() {
+        move_file(&staged_hash_file, &hash_file).context("failed to install hash file")?;
+    }
+    Ok(())
+}
+
+/// Makes the directory `from` available at `dest`. On Unix this is a symlink, elsewhere a copy
+/// (since creating symlinks on Windows requires special privileges). Anything previously at `dest`
+/// gets replaced, unless it already is `from` (or a link to it).
+fn link_dir(from: &Path, dest: &Path) -> Result<()> {
+    if let (Ok(from), Ok(dest)) = (from.canonicalize(), dest.canonicalize()) {
+        if from == dest {
+            return Ok(());
+        }
+    }
+    let parent = dest.parent().unwrap();
+    fs::create_dir_all(parent).context("failed to create parent dir")?;
+    // Prepare the new directory next to the destination, so that the final step is a single
+    // rename.
+    let tmp_dir = TempDir::new_in(parent).context("failed to create temporary dir")?;
+    let new_dir = tmp_dir.path().join("link");
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(from, &new_dir).context("failed to create symlink")?;
+    #[cfg(not(unix))]
+    copy_dir_recursive(from, &new_dir).context("failed to copy directory")?;
+    // A symlink can be renamed over, but a directory cannot.
+    if dest.is_dir() && !dest.is_symlink() {
+        fs::remove_dir_all(dest).context("failed to remove old directory")?;
+    }
+    fs::rename(&new_dir, dest).context("failed to move directory into place")?;
+    Ok(())
+}
+
+/// Runs the command to completion and collects its output, like [`Command::output`]. If it runs
+/// for longer than `timeout`, it gets killed and `None` is returned.
+fn output_with_timeout(cmd: &mut Command, timeout: Option<Duration>) -> Result<Option<Output>> {
+    let Some(timeout) = timeout else {
+        return Ok(Some(cmd.output()?));
+    };
+    let deadline = Instant::now() + timeout;
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Read the pipes in the background so that the child cannot block on a full pipe.
+    fn read_all(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = pipe.read_to_end(&mut buf);
+            buf
+        })
+    }
+    let stdout = read_all(child.stdout.take().unwrap());
+    let stderr = read_all(child.stderr.take().unwrap());
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            // We do not wait for the reader threads: processes spawned by the child might still
+            // hold on to the pipes.
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    Ok(Some(Output {
+        status,
+        stdout: stdout.join().unwrap(),
+        stderr: stderr.join().unwrap(),
+    }))
+}
+
+/// Renders the given string as a TOML (basic) string, including the quotes.
+fn toml_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Renders the given strings as a TOML array.
+fn toml_array(strings: &[String]) -> String {
+    let strings: Vec<String> = strings.iter().map(|s| toml_string(s)).collect();
+    format!("[{}]", strings.join(", "))
+}
+
+/// Returns the files produced for all library targets, based on the JSON messages cargo printed
+/// to stdout (with `--message-format=json`).
+fn cargo_artifacts(cargo_stdout: &[u8]) -> Result<Vec<PathBuf>> {
+    let mut artifacts = Vec::new();
+    for line in cargo_stdout.split(|&b| b == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let msg: serde_json::Value =
+            serde_json::from_slice(line).context("failed to parse cargo JSON message")?;
+        if msg["reason"] != "compiler-artifact" {
+            continue;
+        }
+        // Build scripts are also reported as artifacts, but they are not part of the sysroot.
+        let kinds = msg["target"]["kind"].as_array();
+        if kinds.is_some_and(|kinds| kinds.iter().any(|k| k == "custom-build")) {
+            continue;
+        }
+        let filenames = msg["filenames"]
+            .as_array()
+            .context("cargo artifact message does not list filenames")?;
+        for filename in filenames {
+            let filename = filename
+                .as_str()
+                .context("cargo artifact filename is not a string")?;
+            artifacts.push(PathBuf::from(filename));
+        }
+    }
+    Ok(artifacts)
+}
+
 /// The build mode to use for this sysroot.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum BuildMode {
     /// Do a full sysroot build. Suited for all purposes (like the regular sysroot), but only works
     /// for the host or for targets that have suitable development tools installed.
@@ -129,6 +771,11 @@ pub enum BuildMode {
     /// Do a check-only sysroot build. This is only suited for check-only builds of crates, but on
     /// the plus side it works for *arbitrary* targets without having any special tools installed.
     Check,
+    /// Build the documentation of the sysroot crates. Instead of libraries, this installs the
+    /// generated docs into the `doc` subdirectory of [`SysrootBuilder::target_dir`]. The docs have
+    /// their own hash file in that directory, so they can be installed alongside the libraries of a
+    /// `Build` or `Check` sysroot without replacing them.
+    Doc,
 }
 
 impl BuildMode {
@@ -138,13 +785,17 @@ impl BuildMode {
         match self {
             Build => "build",
             Check => "check",
+            Doc => "doc",
         }
     }
 }
 
 /// Settings controlling how the sysroot will be built.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum SysrootConfig {
+    /// Build a minimal sysroot with only the `core` crate (and `compiler_builtins`).
+    CoreOnly,
     /// Build a no-std (only core and alloc) sysroot.
     NoStd,
     /// Build a full sysroot with the `std` and `test` crates.
@@ -154,16 +805,197 @@ pub enum SysrootConfig {
     },
 }
 
+/// The cargo profile that the sysroot build inherits its settings from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SysrootProfile {
+    /// Inherit from the `release` profile (the default, except for [`BuildMode::Check`]; see
+    /// [`SysrootBuilder::profile`]).
+    Release,
+    /// Inherit from the `dev` profile, e.g. to get a standard library with debug assertions.
+    Dev,
+    /// Inherit from a custom profile. Since the sysroot is built from a generated manifest, that
+    /// profile needs to be defined in a cargo config file.
+    Custom(String),
+}
+
+impl SysrootProfile {
+    /// Returns the name of this profile.
+    pub fn as_str(&self) -> &str {
+        use SysrootProfile::*;
+        match self {
+            Release => "release",
+            Dev => "dev",
+            Custom(name) => name,
+        }
+    }
+}
+
+/// The panic strategy to build the sysroot with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PanicStrategy {
+    /// Build a sysroot that supports unwinding (the default).
+    Unwind,
+    /// Build a sysroot for `-Cpanic=abort`. This also disables the `panic-unwind` feature of the
+    /// standard library.
+    Abort,
+}
+
+impl PanicStrategy {
+    /// Returns a string with the value of the `panic` profile setting matching this strategy.
+    pub fn as_str(&self) -> &str {
+        use PanicStrategy::*;
+        match self {
+            Unwind => "unwind",
+            Abort => "abort",
+        }
+    }
+}
+
+/// The kind of link-time optimization to build the sysroot with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LtoMode {
+    /// No link-time optimization at all.
+    Off,
+    /// ThinLTO.
+    Thin,
+    /// "Fat" LTO across all crates.
+    Fat,
+}
+
+impl LtoMode {
+    /// Returns a string with the value of the `lto` profile setting matching this mode.
+    pub fn as_str(&self) -> &str {
+        use LtoMode::*;
+        match self {
+            Off => "off",
+            Thin => "thin",
+            Fat => "fat",
+        }
+    }
+}
+
+/// How much debug information to build the sysroot with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DebugInfo {
+    /// No debug information.
+    None,
+    /// Only line tables, which is enough for backtraces with file names and line numbers.
+    LineTablesOnly,
+    /// Full debug information.
+    Full,
+}
+
+impl DebugInfo {
+    /// Returns a string with the value of the `debug` profile setting matching this level.
+    pub fn as_str(&self) -> &str {
+        use DebugInfo::*;
+        match self {
+            None => "none",
+            LineTablesOnly => "line-tables-only",
+            Full => "full",
+        }
+    }
+}
+
+/// How much output the sysroot build produces.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Verbosity {
+    /// Pass `--quiet` to cargo, and do not log anything at the `info` level.
+    Quiet,
+    /// Cargo's default output (the default).
+    Normal,
+    /// Pass `-v` to cargo.
+    Verbose,
+    /// Pass `-vv` to cargo, which also shows the output of build scripts.
+    VeryVerbose,
+}
+
 /// Information about a to-be-created sysroot.
 pub struct SysrootBuilder<'a> {
     sysroot_dir: PathBuf,
+    /// The sysroot directory that was passed to `new` (which differs from `sysroot_dir` when
+    /// there is a variant).
+    base_sysroot_dir: PathBuf,
+    variant: Option<String>,
     target: OsString,
     config: SysrootConfig,
     mode: BuildMode,
+    profile: Option<SysrootProfile>,
+    panic_strategy: PanicStrategy,
+    verbosity: Verbosity,
+    debug_assertions: Option<bool>,
+    overflow_checks: Option<bool>,
+    opt_level: Option<String>,
+    lto: Option<LtoMode>,
+    codegen_units: Option<u32>,
+    debuginfo: Option<DebugInfo>,
+    timeout: Option<Duration>,
     rustflags: Vec<OsString>,
+    std_rustflags: Vec<OsString>,
+    target_cpu: Option<String>,
+    force_frame_pointers: bool,
+    target_features: Vec<String>,
+    instrument_coverage: bool,
+    cargo_args: Vec<OsString>,
+    cargo_config: Vec<(String, String)>,
+    envs: Vec<(OsString, OsString)>,
+    offline: bool,
+    locked: bool,
+    frozen: bool,
     cargo: Option<Command>,
+    build_dir: Option<PathBuf>,
+    build_dir_base: Option<PathBuf>,
+    staging_dir: Option<PathBuf>,
+    lib_metadata: Option<String>,
+    hash_file_name: String,
+    force_rebuild: bool,
+    rustc_bootstrap: bool,
+    rustc_wrapper: Option<PathBuf>,
+    rustc: Option<PathBuf>,
+    toolchain: Option<String>,
+    hardlink_artifacts: bool,
+    artifact_extensions: Option<Vec<String>>,
+    proc_macro: bool,
+    include_test: bool,
+    write_hash: bool,
+    profiler: bool,
+    install_sources: bool,
+    incremental_install: bool,
+    keep_build_dir: bool,
     rustc_version: Option<rustc_version::VersionMeta>,
     when_build_required: Option<Box<dyn FnOnce() + 'a>>,
+    on_event: Option<EventHook<'a>>,
+    manifest_hook: Option<ManifestHook<'a>>,
+}
+
+/// The type of the [`SysrootBuilder::on_event`] hook.
+type EventHook<'a> = Box<dyn FnMut(BuildEvent<'_>) + 'a>;
+
+/// The type of the [`SysrootBuilder::manifest_hook`] hook.
+type ManifestHook<'a> = Box<dyn Fn(&Path, &mut String) + 'a>;
+
+/// A step in building a sysroot, as reported to the [`SysrootBuilder::on_event`] hook.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BuildPhase {
+    /// The required sysroot is already cached, so nothing needs to be done.
+    AlreadyCached,
+    /// Cargo is being invoked to build the sysroot.
+    CargoStarted,
+    /// Cargo successfully built the sysroot.
+    CargoFinished,
+    /// The build results are being copied to the staging directory.
+    Staging,
+    /// The sysroot was installed into the sysroot directory.
+    Installed,
+}
+
+/// An event reported to the [`SysrootBuilder::on_event`] hook.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BuildEvent<'b> {
+    /// The target that the sysroot is being built for.
+    pub target: &'b OsStr,
+    /// The step that was reached.
+    pub phase: BuildPhase,
 }
 
 /// Whether a successful [`SysrootBuilder::build_from_source`] call found a cached sysroot or
@@ -176,8 +1008,136 @@ pub enum SysrootStatus {
     SysrootBuilt,
 }
 
-/// Hash file name (in target/lib directory).
-const HASH_FILE_NAME: &str = ".rustc-build-sysroot-hash";
+/// What happened during [`SysrootBuilder::build_from_source_with_report`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildReport {
+    /// Whether a cached sysroot was found or a fresh one was built.
+    pub status: SysrootStatus,
+    /// How long the cargo invocation took (zero if the sysroot was cached).
+    pub duration: Duration,
+    /// The number of library files that were installed into the sysroot (zero if the sysroot was
+    /// cached, or for [`BuildMode::Doc`]).
+    pub artifacts_installed: usize,
+}
+
+impl BuildReport {
+    fn cached() -> Self {
+        BuildReport {
+            status: SysrootStatus::AlreadyCached,
+            duration: Duration::ZERO,
+            artifacts_installed: 0,
+        }
+    }
+}
+
+/// Why [`SysrootBuilder::build_from_source`] would rebuild the sysroot, as determined by
+/// [`SysrootBuilder::rebuild_reason`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RebuildReason {
+    /// There is no sysroot for the configured target yet (or its hash file cannot be read).
+    NotInstalled,
+    /// [`SysrootBuilder::force_rebuild`] is set.
+    ForceRebuild,
+    /// The hash is up-to-date, but the sysroot is incomplete (see [`SysrootBuilder::verify`]).
+    Incomplete,
+    /// The given inputs that are recorded in the hash file (like `"rustflags"` or
+    /// `"rustc_commit"`) differ between the installed sysroot and the current configuration.
+    InputsChanged {
+        /// The names of the inputs that changed.
+        inputs: Vec<String>,
+    },
+    /// The hash differs, but the hash file does not say why: either it was written by an older
+    /// version of this crate, or an input that is not recorded changed (like the contents of the
+    /// library sources).
+    Unknown,
+}
+
+impl fmt::Display for RebuildReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RebuildReason::NotInstalled => write!(f, "no sysroot is installed"),
+            RebuildReason::ForceRebuild => write!(f, "a rebuild was forced"),
+            RebuildReason::Incomplete => write!(f, "the installed sysroot is incomplete"),
+            RebuildReason::InputsChanged { inputs } => {
+                write!(f, "inputs changed: {}", inputs.join(", "))
+            }
+            RebuildReason::Unknown => write!(f, "the sysroot hash changed"),
+        }
+    }
+}
+
+/// What [`SysrootBuilder::build_from_source`] would do, as determined by
+/// [`SysrootBuilder::dry_run`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DryRun {
+    /// Whether a sysroot would be built, i.e., whether there is no up-to-date cached sysroot.
+    pub needs_rebuild: bool,
+    /// The cargo program that would be invoked.
+    pub program: OsString,
+    /// The arguments that cargo would be invoked with. Unless a persistent
+    /// [`SysrootBuilder::build_dir`] is set, paths inside the build directory start with a
+    /// `<tempdir>` placeholder.
+    pub args: Vec<OsString>,
+    /// The environment variables that would be set for cargo (`None` means the variable is
+    /// removed from the environment).
+    pub envs: Vec<(OsString, Option<OsString>)>,
+    /// The directory that the sysroot would be installed to.
+    pub target_dir: PathBuf,
+}
+
+/// A sysroot that is ready to use, as returned by [`ensure_sysroot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SysrootHandle {
+    /// Whether a cached sysroot was found or a fresh one was built.
+    pub status: SysrootStatus,
+    /// The sysroot directory.
+    pub sysroot_dir: PathBuf,
+    /// The directory inside the sysroot that holds everything specific to the target (see
+    /// [`SysrootBuilder::target_dir`]).
+    pub target_dir: PathBuf,
+}
+
+impl SysrootHandle {
+    /// Returns the arguments that need to be passed to rustc to use this sysroot (see
+    /// [`SysrootBuilder::rustc_args`]).
+    pub fn rustc_args(&self) -> Vec<OsString> {
+        vec!["--sysroot".into(), self.sysroot_dir.clone().into()]
+    }
+}
+
+/// Makes sure that `sysroot_dir` contains an up-to-date sysroot for `target`, built from the
+/// sources in `src_dir` with the default configuration for the given mode. This is a shorthand
+/// for the common case; use [`SysrootBuilder`] for more control over the build.
+pub fn ensure_sysroot(
+    sysroot_dir: &Path,
+    target: impl Into<OsString>,
+    src_dir: &Path,
+    mode: BuildMode,
+    rustc_version: rustc_version::VersionMeta,
+    cargo: Command,
+) -> Result<SysrootHandle> {
+    let builder = SysrootBuilder::new(sysroot_dir, target)
+        .build_mode(mode)
+        .rustc_version(rustc_version)
+        .cargo(cargo);
+    let target_dir = builder.target_dir();
+    let status = builder.build_from_source(src_dir)?;
+    Ok(SysrootHandle {
+        status,
+        sysroot_dir: sysroot_dir.to_owned(),
+        target_dir,
+    })
+}
+
+/// Default hash file name (in the target sysroot directory).
+const DEFAULT_HASH_FILE_NAME: &str = ".rustc-build-sysroot-hash";
+
+/// Environment variable that makes us keep the temporary build dir on failure.
+const KEEP_BUILD_DIR_ENV: &str = "RUSTC_BUILD_SYSROOT_KEEP_BUILD_DIR";
+
+/// Lock file name (in the sysroot directory).
+const LOCK_FILE_NAME: &str = ".rustc-build-sysroot-lock";
 
 impl<'a> SysrootBuilder<'a> {
     /// Prepare to create a new sysroot in the given folder (that folder should later be passed to
@@ -198,18 +1158,68 @@ impl<'a> SysrootBuilder<'a> {
         ];
         SysrootBuilder {
             sysroot_dir: sysroot_dir.to_owned(),
+            base_sysroot_dir: sysroot_dir.to_owned(),
+            variant: None,
             target: target.into(),
             config: SysrootConfig::WithStd {
                 std_features: vec![],
             },
             mode: BuildMode::Build,
+            profile: None,
+            panic_strategy: PanicStrategy::Unwind,
+            verbosity: Verbosity::Normal,
+            debug_assertions: None,
+            overflow_checks: None,
+            opt_level: None,
+            lto: None,
+            codegen_units: None,
+            debuginfo: None,
+            timeout: None,
             rustflags: default_flags.iter().map(Into::into).collect(),
+            std_rustflags: vec![],
+            target_cpu: None,
+            force_frame_pointers: false,
+            target_features: vec![],
+            instrument_coverage: false,
+            cargo_args: vec![],
+            cargo_config: vec![],
+            envs: vec![],
+            offline: false,
+            locked: false,
+            frozen: false,
             cargo: None,
+            build_dir: None,
+            build_dir_base: None,
+            staging_dir: None,
+            lib_metadata: None,
+            hash_file_name: DEFAULT_HASH_FILE_NAME.to_owned(),
+            force_rebuild: false,
+            rustc_bootstrap: true,
+            rustc_wrapper: None,
+            rustc: None,
+            toolchain: None,
+            hardlink_artifacts: false,
+            artifact_extensions: None,
+            proc_macro: false,
+            include_test: true,
+            write_hash: true,
+            profiler: false,
+            install_sources: false,
+            incremental_install: false,
+            keep_build_dir: false,
             rustc_version: None,
             when_build_required: None,
+            on_event: None,
+            manifest_hook: None,
         }
     }
 
+    /// Prepare to create a new sysroot in the given folder for the host target of the given
+    /// rustc. This also sets [`SysrootBuilder::rustc_version`].
+    pub fn for_host(sysroot_dir: &Path, rustc_version: rustc_version::VersionMeta) -> Self {
+        Self::new(sysroot_dir, &rustc_version.host).rustc_version(rustc_version)
+    }
+
     /// Sets the build mode (regular build vs check-only build).
     pub fn build_mode(mut self, build_mode: BuildMode) -> Self {
         self.mode = build_mode;
@@ -222,6 +1232,87 @@ impl<'a> SysrootBuilder<'a> {
         self
     }
 
+    /// Sets the cargo profile that the sysroot build inherits its settings from. Defaults to
+    /// [`SysrootProfile::Release`], or for [`BuildMode::Check`] to [`SysrootProfile::Dev`] with the
+    /// `cfg`s of a release build.
+    pub fn profile(mut self, profile: SysrootProfile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Returns the profile to inherit from; see [`SysrootBuilder::profile`].
+    fn profile_or_default(&self) -> SysrootProfile {
+        match &self.profile {
+            Some(profile) => profile.clone(),
+            None if self.mode == BuildMode::Check => SysrootProfile::Dev,
+            None => SysrootProfile::Release,
+        }
+    }
+
+    /// Sets the panic strategy (unwinding vs aborting).
+    pub fn panic_strategy(mut self, panic_strategy: PanicStrategy) -> Self {
+        self.panic_strategy = panic_strategy;
+        self
+    }
+
+    /// Sets whether to enable debug assertions in the sysroot, overriding the setting of the
+    /// [`SysrootBuilder::profile`].
+    pub fn debug_assertions(mut self, debug_assertions: bool) -> Self {
+        self.debug_assertions = Some(debug_assertions);
+        self
+    }
+
+    /// Sets whether to enable overflow checks in the sysroot, overriding the setting of the
+    /// [`SysrootBuilder::profile`].
+    pub fn overflow_checks(mut self, overflow_checks: bool) -> Self {
+        self.overflow_checks = Some(overflow_checks);
+        self
+    }
+
+    /// Sets the optimization level of the sysroot (`0` to `3`, or `s`/`z` to optimize for size),
+    /// overriding the setting of the [`SysrootBuilder::profile`].
+    pub fn opt_level(mut self, opt_level: impl Into<String>) -> Self {
+        self.opt_level = Some(opt_level.into());
+        self
+    }
+
+    /// Sets the link-time optimization mode of the sysroot, overriding the setting of the
+    /// [`SysrootBuilder::profile`]. Code linked with such a sysroot has to use the same
+    /// [`SysrootBuilder::panic_strategy`].
+    pub fn lto(mut self, lto: LtoMode) -> Self {
+        self.lto = Some(lto);
+        self
+    }
+
+    /// Sets the number of codegen units per crate of the sysroot, overriding the setting of the
+    /// [`SysrootBuilder::profile`].
+    ///
+    /// Building fails with [`BuildError::InvalidSetting`] if `codegen_units` is 0.
+    pub fn codegen_units(mut self, codegen_units: u32) -> Self {
+        self.codegen_units = Some(codegen_units);
+        self
+    }
+
+    /// Sets how much debug information the sysroot gets, overriding the setting of the
+    /// [`SysrootBuilder::profile`].
+    pub fn debuginfo(mut self, debuginfo: DebugInfo) -> Self {
+        self.debuginfo = Some(debuginfo);
+        self
+    }
+
+    /// Sets the verbosity of the cargo invocation (and of this crate's logging).
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Sets the maximum time the cargo invocation may take before it is killed (see
+    /// [`BuildError::Timeout`]). By default, there is no timeout.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     /// Appends the given flag.
     ///
     /// If no `--cap-lints` argument is configured, we will add `--cap-lints=warn`.
@@ -242,16 +1333,353 @@ impl<'a> SysrootBuilder<'a> {
         self
     }
 
-    /// Sets the cargo command to call.
-    ///
-    /// This will be invoked with `output()`, so if stdout/stderr should be inherited
-    /// then that needs to be set explicitly.
-    pub fn cargo(mut self, cargo: Command) -> Self {
-        self.cargo = Some(cargo);
+    /// Appends the given flags, which (unlike [`SysrootBuilder::rustflags`]) are only meant for
+    /// building the standard library itself, e.g. `-Z` flags that bootstrap passes.
+    pub fn std_rustflags(
+        mut self,
+        std_rustflags: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Self {
+        self.std_rustflags
+            .extend(std_rustflags.into_iter().map(Into::into));
         self
     }
 
-    /// Sets the rustc version information (in case the user has that available).
+    /// Sets whether to build the sysroot with `-Cforce-frame-pointers=yes`.
+    pub fn force_frame_pointers(mut self, force_frame_pointers: bool) -> Self {
+        self.force_frame_pointers = force_frame_pointers;
+        self
+    }
+
+    /// Sets the CPU to optimize the sysroot for (`-Ctarget-cpu`), e.g. `native`.
+    pub fn target_cpu(mut self, target_cpu: impl Into<String>) -> Self {
+        self.target_cpu = Some(target_cpu.into());
+        self
+    }
+
+    /// Enables the given target features for the sysroot (`-Ctarget-feature`), e.g. `avx2`. A
+    /// feature can also be prefixed with `-` to disable it; without a prefix, `+` is assumed.
+    pub fn target_features(
+        mut self,
+        target_features: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.target_features
+            .extend(target_features.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets whether to build the sysroot with `-Cinstrument-coverage`. This needs the profiler
+    /// runtime (see [`SysrootBuilder::profiler`]).
+    pub fn instrument_coverage(mut self, instrument_coverage: bool) -> Self {
+        self.instrument_coverage = instrument_coverage;
+        self
+    }
+
+    /// Returns all the flags to build the sysroot with: the ones set via
+    /// [`SysrootBuilder::rustflag`], followed by those implied by other settings, and finally
+    /// the [`SysrootBuilder::std_rustflags`].
+    fn all_rustflags(&self) -> Vec<OsString> {
+        let mut rustflags = self.rustflags.clone();
+        let mut add_flag = |flag: String| {
+            // The user might have already passed the same flag manually.
+            if !rustflags.iter().any(|f| *f == *flag) {
+                rustflags.push(flag.into());
+            }
+        };
+        if self.instrument_coverage {
+            add_flag("-Cinstrument-coverage".to_owned());
+        }
+        if self.force_frame_pointers {
+            add_flag("-Cforce-frame-pointers=yes".to_owned());
+        }
+        if let Some(target_cpu) = &self.target_cpu {
+            add_flag(format!("-Ctarget-cpu={target_cpu}"));
+        }
+        if !self.target_features.is_empty() {
+            let target_features: Vec<String> = self
+                .target_features
+                .iter()
+                .map(|feature| {
+                    if feature.starts_with(['+', '-']) {
+                        feature.clone()
+                    } else {
+                        format!("+{feature}")
+                    }
+                })
+                .collect();
+            add_flag(format!("-Ctarget-feature={}", target_features.join(",")));
+        }
+        rustflags.extend(self.std_rustflags.iter().cloned());
+        rustflags
+    }
+
+    /// Appends the given argument to the cargo invocation, after all the arguments that we pass
+    /// ourselves.
+    pub fn cargo_arg(mut self, cargo_arg: impl Into<OsString>) -> Self {
+        self.cargo_args.push(cargo_arg.into());
+        self
+    }
+
+    /// Appends the given arguments to the cargo invocation. See [`SysrootBuilder::cargo_arg`] for
+    /// more explanation.
+    pub fn cargo_args(mut self, cargo_args: impl IntoIterator<Item = impl Into<OsString>>) -> Self {
+        self.cargo_args
+            .extend(cargo_args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Appends the given key/value pairs to the cargo configuration, passed as `--config
+    /// <key>=<value>`. The values are TOML, so strings have to be quoted.
+    ///
+    /// Building fails with [`BuildError::InvalidSetting`] if a key is empty or contains `=` or
+    /// whitespace.
+    pub fn cargo_config(
+        mut self,
+        cargo_config: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    ) -> Self {
+        self.cargo_config.extend(
+            cargo_config
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// Sets an environment variable for the cargo invocation, e.g. `CC` for the C parts of
+    /// `compiler_builtins`. The variables that this crate sets itself take precedence.
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    /// Sets the given environment variables for the cargo invocation. See
+    /// [`SysrootBuilder::env`] for more explanation.
+    pub fn envs(
+        mut self,
+        envs: impl IntoIterator<Item = (impl Into<OsString>, impl Into<OsString>)>,
+    ) -> Self {
+        self.envs.extend(
+            envs.into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// Sets whether to pass `--offline` to cargo.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Sets whether to pass `--locked` to cargo. The lockfile from the sources usually has to be
+    /// updated for our manifest, in which case this makes the build fail.
+    pub fn locked(mut self, locked: bool) -> Self {
+        self.locked = locked;
+        self
+    }
+
+    /// Sets whether to pass `--frozen` to cargo (see [`SysrootBuilder::locked`]).
+    pub fn frozen(mut self, frozen: bool) -> Self {
+        self.frozen = frozen;
+        self
+    }
+
+    /// Returns the cargo command that is used if none is set with [`SysrootBuilder::cargo`]: the
+    /// binary given by the `CARGO` environment variable (which cargo sets when running
+    /// subcommands), or `cargo` otherwise.
+    ///
+    /// This is useful as a starting point for configuring a cargo command.
+    pub fn default_cargo_cmd() -> Command {
+        Command::new(env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo")))
+    }
+
+    /// Sets the cargo command to call. Defaults to [`SysrootBuilder::default_cargo_cmd`].
+    ///
+    /// Its stdout is always captured. Stderr is captured too, unless the command inherits it and no
+    /// [`SysrootBuilder::timeout`] is set.
+    pub fn cargo(mut self, cargo: Command) -> Self {
+        self.cargo = Some(cargo);
+        self
+    }
+
+    /// Sets a persistent directory to build the sysroot in, so that cargo can reuse previous build
+    /// results. By default, a fresh temporary directory is used. A relative path is resolved
+    /// against the current directory.
+    pub fn build_dir(mut self, build_dir: &Path) -> Self {
+        self.build_dir = Some(absolute_path(build_dir));
+        self
+    }
+
+    /// Sets the directory in which the temporary build directory is created (unless
+    /// [`SysrootBuilder::build_dir`] is set). Defaults to the system temp dir. A relative path is
+    /// resolved against the current directory.
+    pub fn build_dir_base(mut self, build_dir_base: &Path) -> Self {
+        self.build_dir_base = Some(absolute_path(build_dir_base));
+        self
+    }
+
+    /// Sets the directory in which the new sysroot is assembled before it gets installed. Defaults
+    /// to the sysroot directory.
+    pub fn staging_dir(mut self, staging_dir: &Path) -> Self {
+        self.staging_dir = Some(staging_dir.to_owned());
+        self
+    }
+
+    /// Sets the data injected into the crate hash of all sysroot crates (via
+    /// `__CARGO_DEFAULT_LIB_METADATA`). Defaults to `rustc-build-sysroot-` followed by a hash of
+    /// the configuration that affects the generated code.
+    pub fn lib_metadata(mut self, lib_metadata: impl Into<String>) -> Self {
+        self.lib_metadata = Some(lib_metadata.into());
+        self
+    }
+
+    /// Returns the value for `__CARGO_DEFAULT_LIB_METADATA`; see [`SysrootBuilder::lib_metadata`].
+    fn lib_metadata_or_default(&self) -> String {
+        if let Some(lib_metadata) = &self.lib_metadata {
+            return lib_metadata.clone();
+        }
+        let mut hasher = DefaultHasher::new();
+        self.target.hash(&mut hasher);
+        self.config.hash(&mut hasher);
+        self.mode.hash(&mut hasher);
+        self.profile_or_default().hash(&mut hasher);
+        self.panic_strategy.hash(&mut hasher);
+        self.all_rustflags().hash(&mut hasher);
+        format!("{DEFAULT_LIB_METADATA}-{:016x}", hasher.finish())
+    }
+
+    /// Sets the name of the file (inside [`SysrootBuilder::target_dir`]) that records the hash of
+    /// the installed sysroot. Defaults to `.rustc-build-sysroot-hash`.
+    pub fn hash_file_name(mut self, hash_file_name: impl Into<String>) -> Self {
+        self.hash_file_name = hash_file_name.into();
+        self
+    }
+
+    /// Sets whether to rebuild the sysroot even if an up-to-date sysroot is already cached.
+    pub fn force_rebuild(mut self, force_rebuild: bool) -> Self {
+        self.force_rebuild = force_rebuild;
+        self
+    }
+
+    /// Sets whether to set `RUSTC_BOOTSTRAP=1` for the sysroot build (the default).
+    pub fn rustc_bootstrap(mut self, rustc_bootstrap: bool) -> Self {
+        self.rustc_bootstrap = rustc_bootstrap;
+        self
+    }
+
+    /// Sets the rustc binary to build the sysroot with (passed to cargo via `RUSTC`). Defaults to
+    /// `$RUSTC`, or `rustc` from the `PATH`.
+    pub fn rustc(mut self, rustc: impl Into<PathBuf>) -> Self {
+        self.rustc = Some(rustc.into());
+        self
+    }
+
+    /// Sets the rustup toolchain to build the sysroot with, e.g. `nightly-2024-06-01`, by passing
+    /// `+<toolchain>` to cargo (and rustc).
+    pub fn toolchain(mut self, toolchain: &str) -> Self {
+        self.toolchain = Some(toolchain.to_owned());
+        self
+    }
+
+    /// Sets the `RUSTC_WRAPPER` to use for the sysroot build, e.g. `sccache`. Unlike the other
+    /// settings, it is not part of the sysroot hash.
+    pub fn rustc_wrapper(mut self, rustc_wrapper: impl Into<PathBuf>) -> Self {
+        self.rustc_wrapper = Some(rustc_wrapper.into());
+        self
+    }
+
+    /// Sets whether to install the build results via hard links instead of copying them, where
+    /// possible.
+    pub fn hardlink_artifacts(mut self, hardlink_artifacts: bool) -> Self {
+        self.hardlink_artifacts = hardlink_artifacts;
+        self
+    }
+
+    /// Sets the file extensions (without the leading `.`) of the build artifacts that get installed
+    /// into the sysroot. Defaults to `rlib`, `rmeta`, `so`, `dylib` and `dll`, or just `rmeta` for
+    /// [`BuildMode::Check`].
+    pub fn artifact_extensions(
+        mut self,
+        artifact_extensions: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.artifact_extensions = Some(artifact_extensions.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Returns the extensions of the artifacts to install.
+    fn artifact_extensions_or_default(&self) -> Vec<&str> {
+        match &self.artifact_extensions {
+            Some(artifact_extensions) => artifact_extensions.iter().map(String::as_str).collect(),
+            // A check-only sysroot only needs the metadata files.
+            None if self.mode == BuildMode::Check => vec!["rmeta"],
+            None => LIBRARY_EXTENSIONS.to_vec(),
+        }
+    }
+
+    /// Returns the extensions of the files in `lib` that belong to the installed sysroot: the
+    /// libraries, and whatever else was configured to be installed.
+    fn library_extensions(&self) -> Vec<&str> {
+        let mut extensions = LIBRARY_EXTENSIONS.to_vec();
+        for extension in self.artifact_extensions_or_default() {
+            if !extensions.contains(&extension) {
+                extensions.push(extension);
+            }
+        }
+        extensions
+    }
+
+    /// Sets whether to also install the library sources into the sysroot, at
+    /// `lib/rustlib/src/rust/library` (like the `rust-src` component).
+    pub fn install_sources(mut self, install_sources: bool) -> Self {
+        self.install_sources = install_sources;
+        self
+    }
+
+    /// Sets whether to build the profiler runtime (`profiler_builtins`) into the sysroot, with
+    /// [`SysrootConfig::WithStd`]. This needs `RUST_COMPILER_RT_FOR_PROFILER` to be set (see
+    /// [`SysrootBuilder::env`]).
+    pub fn profiler(mut self, profiler: bool) -> Self {
+        self.profiler = profiler;
+        self
+    }
+
+    /// Sets whether to write the hash file (the default). Without it, every
+    /// [`SysrootBuilder::build_from_source`] rebuilds the sysroot.
+    pub fn write_hash(mut self, write_hash: bool) -> Self {
+        self.write_hash = write_hash;
+        self
+    }
+
+    /// Sets whether to build the `test` crate into the sysroot (the default), with
+    /// [`SysrootConfig::WithStd`]. Without it, `proc_macro` is only built if
+    /// [`SysrootBuilder::proc_macro`] is set.
+    pub fn include_test(mut self, include_test: bool) -> Self {
+        self.include_test = include_test;
+        self
+    }
+
+    /// Sets whether to build the `proc_macro` crate into the sysroot, with
+    /// [`SysrootConfig::WithStd`].
+    pub fn proc_macro(mut self, proc_macro: bool) -> Self {
+        self.proc_macro = proc_macro;
+        self
+    }
+
+    /// Sets whether to update an existing sysroot in place, only replacing the files that changed.
+    /// This is not atomic, so programs using the sysroot in the meantime may see a mix of old and
+    /// new files.
+    pub fn incremental_install(mut self, incremental_install: bool) -> Self {
+        self.incremental_install = incremental_install;
+        self
+    }
+
+    /// Sets whether to keep the temporary build directory if the build fails (its location is
+    /// included in the error). Setting `RUSTC_BUILD_SYSROOT_KEEP_BUILD_DIR` has the same effect.
+    pub fn keep_build_dir_on_failure(mut self, keep_build_dir: bool) -> Self {
+        self.keep_build_dir = keep_build_dir;
+        self
+    }
+
+    /// Sets the rustc version information (in case the user has that available).
     pub fn rustc_version(mut self, rustc_version: rustc_version::VersionMeta) -> Self {
         self.rustc_version = Some(rustc_version);
         self
@@ -264,12 +1692,55 @@ impl<'a> SysrootBuilder<'a> {
         self
     }
 
+    /// Sets the hook that will be called whenever the sysroot build reaches a new [`BuildPhase`],
+    /// e.g. to show progress.
+    pub fn on_event(mut self, on_event: impl FnMut(BuildEvent<'_>) + 'a) -> Self {
+        self.on_event = Some(Box::new(on_event));
+        self
+    }
+
+    /// Sets a hook that can modify the `Cargo.toml` we generate for building the sysroot. It
+    /// receives the library source directory and the manifest.
+    pub fn manifest_hook(mut self, manifest_hook: impl Fn(&Path, &mut String) + 'a) -> Self {
+        self.manifest_hook = Some(Box::new(manifest_hook));
+        self
+    }
+
+    /// Calls the `on_event` hook, if one was set.
+    fn report(&mut self, phase: BuildPhase) {
+        if let Some(on_event) = &mut self.on_event {
+            on_event(BuildEvent {
+                target: &self.target,
+                phase,
+            });
+        }
+    }
+
+    /// Returns the arguments that need to be passed to rustc to use this sysroot, i.e.,
+    /// `--sysroot <dir>`.
+    ///
+    /// Since [`SysrootBuilder::build_from_source`] consumes the builder, call this before building.
+    pub fn rustc_args(&self) -> Vec<OsString> {
+        vec!["--sysroot".into(), self.sysroot_dir.clone().into()]
+    }
+
+    /// Like [`SysrootBuilder::rustc_args`], but additionally adds the directory holding the
+    /// sysroot libraries for the configured target as a dependency search path, i.e.,
+    /// `-L dependency=<sysroot>/lib/rustlib/<target>/lib`. This is useful when invoking rustc
+    /// directly.
+    pub fn rustc_flags(&self) -> Vec<OsString> {
+        let mut dependency = OsString::from("dependency=");
+        dependency.push(self.target_dir().join("lib"));
+        let mut flags = self.rustc_args();
+        flags.extend(["-L".into(), dependency]);
+        flags
+    }
+
     /// Our configured target can be either a built-in target name, or a path to a target file.
     /// We use the same logic as rustc to tell which is which:
     /// https://github.com/rust-lang/rust/blob/8d39ec1825024f3014e1f847942ac5bbfcf055b0/compiler/rustc_session/src/config.rs#L2252-L2263
     fn target_name(&self) -> &OsStr {
-        let path = Path::new(&self.target);
-        if path.extension().and_then(OsStr::to_str) == Some("json") {
+        if let Some(path) = self.target_spec_file() {
             // Path::file_stem and Path::extension are the last component of the path split on the
             // rightmost '.' so if we have an extension we must have a file_stem.
             path.file_stem().unwrap()
@@ -280,7 +1751,40 @@ impl<'a> SysrootBuilder<'a> {
         }
     }
 
-    fn sysroot_target_dir(&self) -> PathBuf {
+    /// Returns the path to the target specification file, if the configured target is one (see
+    /// [`SysrootBuilder::target_name`]).
+    fn target_spec_file(&self) -> Option<&Path> {
+        let path = Path::new(&self.target);
+        (path.extension().and_then(OsStr::to_str) == Some("json")).then_some(path)
+    }
+
+    /// Makes this a named variant of the sysroot, in `<sysroot_dir>/variants/<variant>`, so that
+    /// differently configured sysroots for the same target can share a sysroot directory.
+    ///
+    /// Building (or cleaning) fails with [`BuildError::InvalidSetting`] if the name is empty or
+    /// contains path separators.
+    pub fn variant(mut self, variant: impl Into<String>) -> Self {
+        let variant = variant.into();
+        self.sysroot_dir = self.base_sysroot_dir.join("variants").join(&variant);
+        self.variant = Some(variant);
+        self
+    }
+
+    /// Returns the directory that the sysroot will be created in (see also
+    /// [`SysrootBuilder::variant`]).
+    pub fn sysroot_dir(&self) -> &Path {
+        &self.sysroot_dir
+    }
+
+    /// Returns the target that the sysroot will be built for (a target name or a path to a target
+    /// specification file).
+    pub fn target(&self) -> &OsStr {
+        &self.target
+    }
+
+    /// Returns the directory inside the sysroot that holds everything specific to the configured
+    /// target, i.e., `<sysroot>/lib/rustlib/<target>`.
+    pub fn target_dir(&self) -> PathBuf {
         self.sysroot_dir
             .join("lib")
             .join("rustlib")
@@ -293,67 +1797,628 @@ impl<'a> SysrootBuilder<'a> {
         src_dir: &Path,
         rustc_version: &rustc_version::VersionMeta,
     ) -> Result<u64> {
-        let mut hasher = DefaultHasher::new();
+        self.sysroot_finish_hash(hash_sources(src_dir)?, src_dir, rustc_version)
+    }
 
-        src_dir.hash(&mut hasher);
-        hash_recursive(src_dir, &mut hasher)?;
+    /// Computes the hash for the sysroot from a hasher that already hashed the sources (see
+    /// [`hash_sources`]).
+    fn sysroot_finish_hash(
+        &self,
+        mut hasher: DefaultHasher,
+        src_dir: &Path,
+        rustc_version: &rustc_version::VersionMeta,
+    ) -> Result<u64> {
+        // Each target has its own hash file, but better safe than sorry.
+        self.target.hash(&mut hasher);
+        self.variant.hash(&mut hasher);
         self.config.hash(&mut hasher);
         self.mode.hash(&mut hasher);
-        self.rustflags.hash(&mut hasher);
+        self.profile_or_default().hash(&mut hasher);
+        self.panic_strategy.hash(&mut hasher);
+        self.all_rustflags().hash(&mut hasher);
+        self.cargo_args.hash(&mut hasher);
+        self.cargo_config.hash(&mut hasher);
+        self.envs.hash(&mut hasher);
+        self.lib_metadata_or_default().hash(&mut hasher);
+        self.artifact_extensions_or_default().hash(&mut hasher);
+        self.install_sources.hash(&mut hasher);
         rustc_version.hash(&mut hasher);
+        self.rustc.hash(&mut hasher);
+        // The manifest is our recipe for building the sysroot, so if it changes (e.g. because a
+        // newer version of this crate generates it differently), we need to rebuild.
+        self.gen_manifest(src_dir).hash(&mut hasher);
+        // The lock file determines the versions of the dependencies we build, but it does not
+        // necessarily live inside `src_dir`.
+        if let Some(lock_file) = find_lock_file(src_dir) {
+            fs::read(lock_file)
+                .context("failed to read lockfile")?
+                .hash(&mut hasher);
+        }
+        // For custom targets, changes to the target spec also require a rebuild.
+        if let Some(target_spec_file) = self.target_spec_file() {
+            if !target_spec_file.is_file() {
+                return Err(BuildError::UnknownTarget {
+                    target: self.target.clone(),
+                }
+                .into());
+            }
+            fs::read(target_spec_file)
+                .context("failed to read target spec file")?
+                .hash(&mut hasher);
+        }
 
         Ok(hasher.finish())
     }
 
+    /// Renders the contents of the hash file: a JSON document with the hash and (for debugging
+    /// purposes only) the main inputs that went into it.
+    fn hash_file_contents(
+        &self,
+        hash: u64,
+        src_dir: &Path,
+        rustc_version: &rustc_version::VersionMeta,
+    ) -> String {
+        let mut contents = serde_json::Map::new();
+        contents.insert("hash".to_owned(), hash.into());
+        contents.extend(self.hash_inputs(src_dir, rustc_version));
+        serde_json::to_string_pretty(&contents).unwrap()
+    }
+
+    /// The inputs of the sysroot hash that we record in the hash file.
+    fn hash_inputs(
+        &self,
+        src_dir: &Path,
+        rustc_version: &rustc_version::VersionMeta,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let (config, std_features): (_, &[String]) = match &self.config {
+            SysrootConfig::CoreOnly => ("core-only", &[]),
+            SysrootConfig::NoStd => ("no-std", &[]),
+            SysrootConfig::WithStd { std_features } => ("with-std", std_features),
+        };
+        let lossy = |strings: &[OsString]| -> Vec<String> {
+            strings
+                .iter()
+                .map(|s| s.to_string_lossy().into_owned())
+                .collect()
+        };
+        let inputs = serde_json::json!({
+            "src_dir": src_dir.to_string_lossy(),
+            "rustc_version": rustc_version.semver.to_string(),
+            "rustc_commit": rustc_version.commit_hash,
+            "target": self.target.to_string_lossy(),
+            "mode": self.mode.as_str(),
+            "profile": self.profile_or_default().as_str(),
+            "panic_strategy": self.panic_strategy.as_str(),
+            "config": config,
+            "std_features": std_features,
+            "rustflags": lossy(&self.all_rustflags()),
+            "cargo_args": lossy(&self.cargo_args),
+            "cargo_config": self
+                .cargo_config
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>(),
+            "lib_metadata": self.lib_metadata_or_default(),
+        });
+        match inputs {
+            serde_json::Value::Object(inputs) => inputs,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Reads the inputs recorded in the hash file, if it has any.
+    fn sysroot_read_hash_inputs(&self) -> Option<serde_json::Map<String, serde_json::Value>> {
+        let hash_file = self.target_dir().join(self.hash_file_path());
+        let contents = fs::read_to_string(hash_file).ok()?;
+        match serde_json::from_str(&contents).ok()? {
+            serde_json::Value::Object(mut contents) => {
+                contents.remove("hash");
+                Some(contents)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the path of the hash file for the configured build mode, relative to
+    /// [`SysrootBuilder::target_dir`].
+    fn hash_file_path(&self) -> PathBuf {
+        if self.mode == BuildMode::Doc {
+            Path::new("doc").join(&self.hash_file_name)
+        } else {
+            PathBuf::from(&self.hash_file_name)
+        }
+    }
+
     fn sysroot_read_hash(&self) -> Option<u64> {
-        let hash_file = self.sysroot_target_dir().join(HASH_FILE_NAME);
-        let hash = fs::read_to_string(&hash_file).ok()?;
-        hash.parse().ok()
+        let hash_file = self.target_dir().join(self.hash_file_path());
+        // In all the error cases below, we just rebuild the sysroot (which will write a fresh hash
+        // file). A missing file is expected, but anything else is worth noting.
+        let contents = match fs::read_to_string(&hash_file) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                warn!(
+                    "failed to read sysroot hash file {}: {err}",
+                    hash_file.display()
+                );
+                return None;
+            }
+        };
+        // Older versions of this crate stored just the hash, which conveniently is valid JSON.
+        let hash = match serde_json::from_str(&contents) {
+            Ok(serde_json::Value::Object(contents)) => {
+                contents.get("hash").and_then(|h| h.as_u64())
+            }
+            Ok(hash) => hash.as_u64(),
+            Err(_) => None,
+        };
+        if hash.is_none() {
+            warn!(
+                "ignoring malformed sysroot hash file {}",
+                hash_file.display()
+            );
+        }
+        hash
+    }
+
+    /// Makes the sysroot for the configured target (i.e., [`SysrootBuilder::target_dir`]) a link
+    /// to `target_dir`, which should be the target dir of another sysroot that was already built.
+    /// This way, many sysroots can share the same build without duplicating the files. Anything
+    /// that was previously installed for the configured target is replaced.
+    ///
+    /// On Unix, this creates a symlink. Elsewhere (in particular on Windows, where creating
+    /// symlinks requires special privileges), the directory is copied instead.
+    ///
+    /// Building a sysroot for the configured target later replaces the link, and leaves
+    /// `target_dir` alone.
+    pub fn link_target_dir(&self, target_dir: &Path) -> Result<()> {
+        if !target_dir.is_dir() {
+            bail!(
+                "sysroot target dir `{}` does not exist",
+                target_dir.display()
+            );
+        }
+        // Make the link point to an absolute path, so it does not depend on where it lives.
+        let target_dir = target_dir
+            .canonicalize()
+            .context("failed to canonicalize sysroot target dir")?;
+        self.check_variant()?;
+        let _sysroot_lock = lock_dir(&self.sysroot_dir)?;
+        link_dir(&target_dir, &self.target_dir()).context("failed to link sysroot target dir")
+    }
+
+    /// Removes the sysroot for the configured target (i.e., [`SysrootBuilder::target_dir`]),
+    /// including its hash file. Does nothing if there is no such sysroot.
+    pub fn clean(&self) -> Result<()> {
+        self.check_variant()?;
+        let target_dir = self.target_dir();
+        if !target_dir.exists() {
+            return Ok(());
+        }
+        let _sysroot_lock = lock_dir(&self.sysroot_dir)?;
+        fs::remove_dir_all(&target_dir).context("failed to remove sysroot target dir")?;
+        Ok(())
+    }
+
+    /// Removes the entire sysroot directory, including the sysroots for all other targets.
+    /// Does nothing if the directory does not exist.
+    ///
+    /// Unlike [`SysrootBuilder::clean`], this does not wait for concurrent builds to finish.
+    pub fn clean_all(&self) -> Result<()> {
+        self.check_variant()?;
+        if !self.sysroot_dir.exists() {
+            return Ok(());
+        }
+        fs::remove_dir_all(&self.sysroot_dir).context("failed to remove sysroot dir")?;
+        Ok(())
+    }
+
+    /// Returns the names of all targets that have a sysroot built by this crate in the sysroot
+    /// directory (identified by their hash file), sorted by name. Returns an empty list if the
+    /// sysroot directory does not exist.
+    pub fn installed_targets(&self) -> Result<Vec<String>> {
+        let rustlib_dir = self.sysroot_dir.join("lib").join("rustlib");
+        if !rustlib_dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut targets = Vec::new();
+        for entry in fs::read_dir(&rustlib_dir).context("failed to read sysroot dir")? {
+            let entry = entry.context("failed to read sysroot dir")?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            // Skip the backup of an old sysroot that is left behind by an interrupted
+            // installation.
+            if name.ends_with(".old") {
+                continue;
+            }
+            if entry.path().join(&self.hash_file_name).is_file() {
+                targets.push(name);
+            }
+        }
+        targets.sort();
+        Ok(targets)
+    }
+
+    /// Returns whether a sysroot with the given hash is already installed (and we are allowed to use
+    /// it).
+    fn is_cached(&self, cur_hash: u64) -> bool {
+        if self.force_rebuild || self.sysroot_read_hash() != Some(cur_hash) {
+            return false;
+        }
+        // The hash file is installed last, but the sysroot could still have been damaged later.
+        match self.verify() {
+            Ok(true) => true,
+            Ok(false) => {
+                warn!(
+                    "sysroot in {} is incomplete, rebuilding it",
+                    self.target_dir().display()
+                );
+                false
+            }
+            Err(err) => {
+                warn!(
+                    "failed to verify sysroot in {}, rebuilding it: {err:#}",
+                    self.target_dir().display()
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns the rustc command to use for querying information about the target.
+    fn rustc_command(&self) -> Command {
+        match &self.rustc {
+            Some(rustc) => Command::new(rustc),
+            None => {
+                let mut cmd =
+                    Command::new(env::var_os("RUSTC").unwrap_or_else(|| OsString::from("rustc")));
+                if let Some(toolchain) = &self.toolchain {
+                    cmd.arg(format!("+{toolchain}"));
+                }
+                cmd
+            }
+        }
+    }
+
+    /// Prepares looking at the given sources and the toolchain (see [`SourceProbe`]).
+    fn source_probe(&self, src_dir: &Path) -> SourceProbe {
+        SourceProbe {
+            src_dir: src_dir.to_owned(),
+            rustc: self.rustc_command(),
+            rustc_version: self.rustc_version.clone(),
+            // If there is no explicit rustc version, it is determined from the toolchain anyway.
+            toolchain: self
+                .toolchain
+                .clone()
+                .filter(|_| self.rustc_version.is_some()),
+        }
+    }
+
+    /// Prepares the checks of the configured target (see [`TargetProbe`]).
+    fn target_probe(&self) -> TargetProbe {
+        TargetProbe {
+            rustc: self.rustc_command(),
+            target: self.target.clone(),
+            target_name: self.target_name().to_owned(),
+            target_spec_file: self.target_spec_file().map(ToOwned::to_owned),
+            with_std: matches!(self.config, SysrootConfig::WithStd { .. }),
+        }
+    }
+
+    /// Returns the configured rustc version, or determines it if none was configured.
+    fn get_rustc_version(&self) -> Result<rustc_version::VersionMeta> {
+        match &self.rustc_version {
+            Some(v) => Ok(v.clone()),
+            None => Ok(rustc_version::VersionMeta::for_command(
+                self.rustc_command(),
+            )?),
+        }
+    }
+
+    /// Returns whether the sysroot needs to be (re)built, i.e., whether
+    /// [`SysrootBuilder::build_from_source`] would have to compile a fresh sysroot for the given
+    /// sources.
+    pub fn needs_rebuild(&self, src_dir: &Path) -> Result<bool> {
+        let cur_hash = self.current_hash(src_dir)?;
+        Ok(!self.is_cached(cur_hash))
+    }
+
+    /// Returns whether the installed sysroot for the configured target can be used with the given
+    /// rustc, i.e., whether it is complete and was built for that exact rustc version, from the
+    /// given sources, with the current configuration. (Unlike [`SysrootBuilder::needs_rebuild`],
+    /// this ignores [`SysrootBuilder::rustc_version`] and [`SysrootBuilder::force_rebuild`].)
+    pub fn is_compatible_with(
+        &self,
+        rustc_version: &rustc_version::VersionMeta,
+        src_dir: &Path,
+    ) -> Result<bool> {
+        let src_dir = find_library_dir(src_dir)?;
+        let hash = self.sysroot_compute_hash(&src_dir, rustc_version)?;
+        Ok(self.sysroot_read_hash() == Some(hash) && self.verify()?)
+    }
+
+    /// Returns the hash of a sysroot built from the given sources with the current configuration.
+    /// The sysroot is rebuilt whenever this differs from [`SysrootBuilder::installed_hash`].
+    pub fn current_hash(&self, src_dir: &Path) -> Result<u64> {
+        let src_dir = find_library_dir(src_dir)?;
+        let rustc_version = self.get_rustc_version()?;
+        self.sysroot_compute_hash(&src_dir, &rustc_version)
+    }
+
+    /// Returns why [`SysrootBuilder::build_from_source`] would rebuild the sysroot for the given
+    /// sources, or `None` if the installed sysroot is up-to-date.
+    pub fn rebuild_reason(&self, src_dir: &Path) -> Result<Option<RebuildReason>> {
+        let src_dir = find_library_dir(src_dir)?;
+        let rustc_version = self.get_rustc_version()?;
+        let cur_hash = self.sysroot_compute_hash(&src_dir, &rustc_version)?;
+        self.rebuild_reason_for(cur_hash, &src_dir, &rustc_version)
+    }
+
+    /// Like [`SysrootBuilder::rebuild_reason`], given the current hash and its inputs.
+    fn rebuild_reason_for(
+        &self,
+        cur_hash: u64,
+        src_dir: &Path,
+        rustc_version: &rustc_version::VersionMeta,
+    ) -> Result<Option<RebuildReason>> {
+        if self.force_rebuild {
+            return Ok(Some(RebuildReason::ForceRebuild));
+        }
+        let Some(installed_hash) = self.sysroot_read_hash() else {
+            return Ok(Some(RebuildReason::NotInstalled));
+        };
+        if installed_hash == cur_hash {
+            return Ok((!self.verify()?).then_some(RebuildReason::Incomplete));
+        }
+        let Some(installed_inputs) = self.sysroot_read_hash_inputs() else {
+            return Ok(Some(RebuildReason::Unknown));
+        };
+        let inputs: Vec<String> = self
+            .hash_inputs(src_dir, rustc_version)
+            .into_iter()
+            .filter(|(name, value)| installed_inputs.get(name) != Some(value))
+            .map(|(name, _)| name)
+            .collect();
+        Ok(Some(if inputs.is_empty() {
+            RebuildReason::Unknown
+        } else {
+            RebuildReason::InputsChanged { inputs }
+        }))
+    }
+
+    /// Returns the hash of the sysroot that is currently installed for the configured target, if
+    /// any.
+    pub fn installed_hash(&self) -> Option<u64> {
+        self.sysroot_read_hash()
+    }
+
+    /// Checks that the sysroot installed for the configured target looks complete: the hash file
+    /// can be read, and the installed files include the main crate of the configured
+    /// [`SysrootConfig`] (e.g. `libstd-*.rlib` for a sysroot with std), or the documentation in
+    /// [`BuildMode::Doc`].
+    ///
+    /// [`SysrootBuilder::build_from_source`] does this as well, and rebuilds the sysroot if the
+    /// check fails, even if the hash is up-to-date.
+    pub fn verify(&self) -> Result<bool> {
+        if self.sysroot_read_hash().is_none() {
+            return Ok(false);
+        }
+        let target_dir = self.target_dir();
+        if self.mode == BuildMode::Doc {
+            return Ok(target_dir.join("doc").is_dir());
+        }
+        let main_crate = match self.config {
+            SysrootConfig::CoreOnly => "core",
+            SysrootConfig::NoStd => "alloc",
+            SysrootConfig::WithStd { .. } => "std",
+        };
+        let prefix = format!("lib{main_crate}-");
+        let extensions = self.library_extensions();
+        let entries = match fs::read_dir(target_dir.join("lib")) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err).context("failed to read sysroot lib dir"),
+        };
+        for entry in entries {
+            let path = entry.context("failed to read sysroot lib dir")?.path();
+            let is_main_crate = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| name.starts_with(&prefix));
+            let has_extension = path
+                .extension()
+                .and_then(OsStr::to_str)
+                .is_some_and(|extension| extensions.contains(&extension));
+            if is_main_crate && has_extension {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Checks the settings that the setters could not reject, since they do not return errors.
+    fn check_settings(&self) -> Result<()> {
+        let invalid = |setting, reason: &str| {
+            Err(BuildError::InvalidSetting {
+                setting,
+                reason: reason.to_owned(),
+            }
+            .into())
+        };
+        if self.codegen_units == Some(0) {
+            return invalid("codegen_units", "the number of codegen units must not be 0");
+        }
+        for (key, _) in &self.cargo_config {
+            if key.is_empty() || key.contains(|c: char| c == '=' || c.is_whitespace()) {
+                return invalid("cargo_config", &format!("invalid key `{key}`"));
+            }
+        }
+        self.check_variant()
+    }
+
+    /// Checks that the variant name (if any) makes [`SysrootBuilder::sysroot_dir`] a directory
+    /// inside `variants`, before anything is done to that directory.
+    fn check_variant(&self) -> Result<()> {
+        match &self.variant {
+            Some(variant)
+                if variant.is_empty()
+                    || variant == "."
+                    || variant == ".."
+                    || variant.contains(['/', '\\']) =>
+            {
+                Err(BuildError::InvalidSetting {
+                    setting: "variant",
+                    reason: format!("invalid name `{variant}`"),
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Make sure that all the crates we are going to depend on exist in `src_dir`, so that we can
+    /// give a clear error before involving cargo.
+    fn check_src_dir(&self, src_dir: &Path) -> Result<()> {
+        let crates: &[&str] = match &self.config {
+            SysrootConfig::CoreOnly => &["core"],
+            SysrootConfig::NoStd => &["core", "alloc"],
+            SysrootConfig::WithStd { .. } if !self.include_test => &["core", "alloc", "std"],
+            SysrootConfig::WithStd { .. } if src_dir.join("sysroot").exists() => {
+                &["core", "alloc", "std", "sysroot"]
+            }
+            SysrootConfig::WithStd { .. } => &["core", "alloc", "std", "test"],
+        };
+        let with_std = matches!(self.config, SysrootConfig::WithStd { .. });
+        let proc_macro: &[&str] = if with_std && self.proc_macro {
+            &["proc_macro"]
+        } else {
+            &[]
+        };
+        let profiler: &[&str] = if with_std && self.profiler {
+            &["profiler_builtins"]
+        } else {
+            &[]
+        };
+        for krate in crates.iter().chain(proc_macro).chain(profiler) {
+            let manifest = src_dir.join(krate).join("Cargo.toml");
+            if !manifest.exists() {
+                return Err(BuildError::IncompleteSource { path: manifest }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the `Cargo.toml` that [`SysrootBuilder::build_from_source`] would use to build the
+    /// sysroot from the given sources (including the changes made by the
+    /// [`SysrootBuilder::manifest_hook`]).
+    pub fn manifest(&self, src_dir: &Path) -> Result<String> {
+        let src_dir = find_library_dir(src_dir)?;
+        Ok(self.gen_manifest(&src_dir))
     }
 
     /// Generate the contents of the manifest file for the sysroot build.
     fn gen_manifest(&self, src_dir: &Path) -> String {
         let have_sysroot_crate = src_dir.join("sysroot").exists();
-        let crates = match &self.config {
+        // The path of the given crate, as a TOML string.
+        let path = |krate: &str| toml_string(&src_dir.join(krate).to_string_lossy());
+        // Older sources call the feature for unwinding support `panic_unwind`.
+        let panic_unwind = |features: &CrateFeatures| {
+            if features.names.iter().any(|name| name == "panic_unwind") {
+                "panic_unwind"
+            } else {
+                "panic-unwind"
+            }
+        };
+        let mut crates = match &self.config {
+            SysrootConfig::CoreOnly => format!(
+                r#"
+[dependencies.core]
+path = {src_dir_core}
+[dependencies.compiler_builtins]
+features = ["rustc-dep-of-std", "mem"]
+version = "*"
+                "#,
+                src_dir_core = path("core"),
+            ),
             SysrootConfig::NoStd => format!(
                 r#"
 [dependencies.core]
-path = {src_dir_core:?}
+path = {src_dir_core}
 [dependencies.alloc]
-path = {src_dir_alloc:?}
+path = {src_dir_alloc}
 [dependencies.compiler_builtins]
 features = ["rustc-dep-of-std", "mem"]
 version = "*"
                 "#,
-                src_dir_core = src_dir.join("core"),
-                src_dir_alloc = src_dir.join("alloc"),
+                src_dir_core = path("core"),
+                src_dir_alloc = path("alloc"),
             ),
-            SysrootConfig::WithStd { std_features } if have_sysroot_crate => format!(
-                r#"
+            SysrootConfig::WithStd { std_features } if !self.include_test => {
+                let mut std_features = std_features.clone();
+                // This is normally enabled via the default features of `sysroot` (or `test`, in
+                // older sources).
+                if self.panic_strategy == PanicStrategy::Unwind {
+                    let features = crate_features(src_dir, "std");
+                    std_features.push(panic_unwind(&features).to_owned());
+                }
+                format!(
+                    r#"
 [dependencies.std]
-features = {std_features:?}
-path = {src_dir_std:?}
-[dependencies.sysroot]
-path = {src_dir_sysroot:?}
+features = {std_features}
+path = {src_dir_std}
                 "#,
-                std_features = std_features,
-                src_dir_std = src_dir.join("std"),
-                src_dir_sysroot = src_dir.join("sysroot"),
-            ),
-            // Fallback for old rustc where the main crate was `test`, not `sysroot`
-            SysrootConfig::WithStd { std_features } => format!(
-                r#"
+                    std_features = toml_array(&std_features),
+                    src_dir_std = path("std"),
+                )
+            }
+            SysrootConfig::WithStd { std_features } => {
+                // Fallback for old rustc where the main crate was `test`, not `sysroot`
+                let main_crate = if have_sysroot_crate {
+                    "sysroot"
+                } else {
+                    "test"
+                };
+                let mut crates = format!(
+                    r#"
 [dependencies.std]
-features = {std_features:?}
-path = {src_dir_std:?}
-[dependencies.test]
-path = {src_dir_test:?}
+features = {std_features}
+path = {src_dir_std}
+[dependencies.{main_crate}]
+path = {src_dir_main}
                 "#,
-                std_features = std_features,
-                src_dir_std = src_dir.join("std"),
-                src_dir_test = src_dir.join("test"),
-            ),
+                    std_features = toml_array(std_features),
+                    src_dir_std = path("std"),
+                    src_dir_main = path(main_crate),
+                );
+                // `panic-unwind` is a default feature of the main crate, so to get rid of it we
+                // have to disable the default features and enable all the others again.
+                if self.panic_strategy == PanicStrategy::Abort {
+                    let features = crate_features(src_dir, main_crate);
+                    let panic_unwind = panic_unwind(&features);
+                    let mut default = features.default;
+                    default.retain(|feature| feature != panic_unwind);
+                    crates.push_str(&format!(
+                        "default-features = false\nfeatures = {}\n",
+                        toml_array(&default)
+                    ));
+                }
+                crates
+            }
         };
+        let with_std = matches!(self.config, SysrootConfig::WithStd { .. });
+        if with_std && self.proc_macro {
+            crates.push_str(&format!(
+                "[dependencies.proc_macro]\npath = {}\n",
+                path("proc_macro")
+            ));
+        }
+        if with_std && self.profiler {
+            crates.push_str(&format!(
+                "[dependencies.profiler_builtins]\npath = {}\n",
+                path("profiler_builtins")
+            ));
+        }
 
         // If we include a patch for rustc-std-workspace-std for no_std sysroot builds, we get a
         // warning from Cargo that the patch is unused. If this patching ever breaks that lint will
@@ -361,30 +2426,57 @@ path = {src_dir_test:?}
         // Currently the only user of rustc-std-workspace-alloc is std_detect, which is only used
         // by std. So we only need to patch rustc-std-workspace-core in no_std sysroot builds, or
         // that patch also produces a warning.
-        let patches = match &self.config {
-            SysrootConfig::NoStd => format!(
-                r#"
-[patch.crates-io.rustc-std-workspace-core]
-path = {src_dir_workspace_core:?}
-                "#,
-                src_dir_workspace_core = src_dir.join("rustc-std-workspace-core"),
-            ),
-            SysrootConfig::WithStd { .. } => format!(
-                r#"
-[patch.crates-io.rustc-std-workspace-core]
-path = {src_dir_workspace_core:?}
-[patch.crates-io.rustc-std-workspace-alloc]
-path = {src_dir_workspace_alloc:?}
-[patch.crates-io.rustc-std-workspace-std]
-path = {src_dir_workspace_std:?}
-                "#,
-                src_dir_workspace_core = src_dir.join("rustc-std-workspace-core"),
-                src_dir_workspace_alloc = src_dir.join("rustc-std-workspace-alloc"),
-                src_dir_workspace_std = src_dir.join("rustc-std-workspace-std"),
-            ),
+        let workspace_crates: &[&str] = match &self.config {
+            SysrootConfig::CoreOnly | SysrootConfig::NoStd => &["rustc-std-workspace-core"],
+            SysrootConfig::WithStd { .. } => &[
+                "rustc-std-workspace-core",
+                "rustc-std-workspace-alloc",
+                "rustc-std-workspace-std",
+            ],
         };
+        // Not all source distributions contain all of these crates, and cargo errors when a patch
+        // points to a path that does not exist. So we only patch the ones that are present.
+        let mut patches = String::new();
+        for workspace_crate in workspace_crates {
+            if src_dir.join(workspace_crate).exists() {
+                patches.push_str(&format!(
+                    "[patch.crates-io.{workspace_crate}]\npath = {}\n",
+                    path(workspace_crate)
+                ));
+            }
+        }
 
-        format!(
+        let base_profile = toml_string(self.profile_or_default().as_str());
+        let panic_strategy = self.panic_strategy.as_str();
+        // For check builds, we only use the dev profile to avoid optimizations; the `cfg`s should
+        // still match the release profile.
+        let release_cfgs = self.profile.is_none() && self.mode == BuildMode::Check;
+        // Settings that override the base profile only if the user asked for it.
+        let mut profile_overrides = String::new();
+        if let Some(debug_assertions) = self.debug_assertions.or(release_cfgs.then_some(false)) {
+            profile_overrides.push_str(&format!("debug-assertions = {debug_assertions}\n"));
+        }
+        if let Some(overflow_checks) = self.overflow_checks.or(release_cfgs.then_some(false)) {
+            profile_overrides.push_str(&format!("overflow-checks = {overflow_checks}\n"));
+        }
+        if let Some(opt_level) = &self.opt_level {
+            // Cargo wants numeric levels as integers, but `s` and `z` as strings.
+            if opt_level.parse::<u8>().is_ok() {
+                profile_overrides.push_str(&format!("opt-level = {opt_level}\n"));
+            } else {
+                profile_overrides.push_str(&format!("opt-level = {}\n", toml_string(opt_level)));
+            }
+        }
+        if let Some(debuginfo) = self.debuginfo {
+            profile_overrides.push_str(&format!("debug = {}\n", toml_string(debuginfo.as_str())));
+        }
+        if let Some(codegen_units) = self.codegen_units {
+            profile_overrides.push_str(&format!("codegen-units = {codegen_units}\n"));
+        }
+        if let Some(lto) = self.lto {
+            profile_overrides.push_str(&format!("lto = {}\n", toml_string(lto.as_str())));
+        }
+        let mut manifest = format!(
             r#"
 [package]
 authors = ["rustc-build-sysroot"]
@@ -396,10 +2488,11 @@ version = "0.0.0"
 path = "lib.rs"
 
 [profile.{DEFAULT_SYSROOT_PROFILE}]
-# We inherit from the local release profile, but then overwrite some
+# We inherit from the configured base profile, but then overwrite some
 # settings to ensure we still get a working sysroot.
-inherits = "release"
-panic = 'unwind'
+inherits = {base_profile}
+panic = '{panic_strategy}'
+{profile_overrides}
 
 {crates}
 
@@ -408,35 +2501,299 @@ panic = 'unwind'
 [patch.crates-io]
 compiler_builtins = {{ git = "https://github.com/LucasSte/compiler-builtins", tag = "lucas-v1.42" }}
             "#
-        )
+        );
+        if let Some(manifest_hook) = &self.manifest_hook {
+            manifest_hook(src_dir, &mut manifest);
+        }
+        manifest
+    }
+
+    /// Prepares the given cargo command for building the sysroot with the workspace in
+    /// `build_dir`.
+    fn cargo_command(&self, mut cmd: Command, build_dir: &Path) -> Command {
+        if let Some(toolchain) = &self.toolchain {
+            // This has to come first, so that the rustup proxy recognizes it.
+            cmd.arg(format!("+{toolchain}"));
+        }
+        cmd.arg(self.mode.as_str());
+        cmd.arg("--profile");
+        cmd.arg(DEFAULT_SYSROOT_PROFILE);
+        cmd.arg("--manifest-path");
+        cmd.arg(build_dir.join("Cargo.toml"));
+        cmd.arg("--target");
+        cmd.arg(&self.target);
+        match self.verbosity {
+            Verbosity::Quiet => {
+                cmd.arg("--quiet");
+            }
+            Verbosity::Normal => {}
+            Verbosity::Verbose => {
+                cmd.arg("-v");
+            }
+            Verbosity::VeryVerbose => {
+                cmd.arg("-vv");
+            }
+        }
+        // User-provided environment variables go first, so that ours take precedence.
+        cmd.envs(self.envs.iter().map(|(key, value)| (key, value)));
+        // Set rustflags.
+        cmd.env(
+            "CARGO_ENCODED_RUSTFLAGS",
+            encode_rustflags(&self.all_rustflags()),
+        );
+        // Make sure the results end up where we expect them.
+        cmd.env("CARGO_TARGET_DIR", build_dir.join("target"));
+        // To avoid metadata conflicts, we need to inject some custom data into the crate hash.
+        // bootstrap does the same at
+        // <https://github.com/rust-lang/rust/blob/c8e12cc8bf0de646234524924f39c85d9f3c7c37/src/bootstrap/builder.rs#L1613>.
+        cmd.env(
+            "__CARGO_DEFAULT_LIB_METADATA",
+            self.lib_metadata_or_default(),
+        );
+        if self.rustc_bootstrap {
+            cmd.env("RUSTC_BOOTSTRAP", "1");
+        }
+        if let Some(rustc) = &self.rustc {
+            cmd.env("RUSTC", rustc);
+        }
+        if let Some(rustc_wrapper) = &self.rustc_wrapper {
+            cmd.env("RUSTC_WRAPPER", rustc_wrapper);
+        }
+
+        if self.offline {
+            cmd.arg("--offline");
+        }
+        if self.locked {
+            cmd.arg("--locked");
+        }
+        if self.frozen {
+            cmd.arg("--frozen");
+        }
+
+        // We determine the files that need to be installed from cargo's output.
+        cmd.arg("--message-format=json-render-diagnostics");
+        cmd.stdout(Stdio::piped());
+        for (key, value) in &self.cargo_config {
+            cmd.arg("--config");
+            cmd.arg(format!("{key}={value}"));
+        }
+        // Extra arguments go last.
+        cmd.args(&self.cargo_args);
+        cmd
+    }
+
+    /// Determines what [`SysrootBuilder::build_from_source`] would do with the given sources,
+    /// without running cargo or changing anything on disk.
+    pub fn dry_run(&self, src_dir: &Path) -> Result<DryRun> {
+        self.check_settings()?;
+        let needs_rebuild = self.needs_rebuild(src_dir)?;
+        let cargo = self
+            .cargo
+            .as_ref()
+            .map_or_else(Self::default_cargo_cmd, clone_command);
+        let build_dir = self
+            .build_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("<tempdir>"));
+        let cmd = self.cargo_command(cargo, &build_dir);
+        Ok(DryRun {
+            needs_rebuild,
+            program: cmd.get_program().to_owned(),
+            args: cmd.get_args().map(ToOwned::to_owned).collect(),
+            envs: cmd
+                .get_envs()
+                .map(|(key, value)| (key.to_owned(), value.map(ToOwned::to_owned)))
+                .collect(),
+            target_dir: self.target_dir(),
+        })
     }
 
     /// Build the `self` sysroot from the given sources.
     ///
-    /// `src_dir` must be the `library` source folder, i.e., the one that contains `std/Cargo.toml`.
+    /// `src_dir` should be the `library` source folder, i.e., the one that contains
+    /// `std/Cargo.toml`. For convenience, it can also be the root of a rust checkout, or a sysroot
+    /// that has the `rust-src` component installed.
+    ///
+    /// The sources are only ever read (cargo builds them as path dependencies, and puts all
+    /// outputs into the build directory), so they may be read-only, as they often are for
+    /// `rust-src`.
     pub fn build_from_source(mut self, src_dir: &Path) -> Result<SysrootStatus> {
-        // A bit of preparation.
-        if !src_dir.join("std").join("Cargo.toml").exists() {
+        Ok(self.build_target_from_source(src_dir)?.status)
+    }
+
+    /// Like [`SysrootBuilder::build_from_source`], but returns more details about the build.
+    pub fn build_from_source_with_report(mut self, src_dir: &Path) -> Result<BuildReport> {
+        self.build_target_from_source(src_dir)
+    }
+
+    /// Like [`SysrootBuilder::build_from_source`], but without blocking the async runtime:
+    /// everything that can take a while (hashing the sources, running rustc and cargo, writing the
+    /// cargo workspace and installing the sysroot) happens on tokio's blocking thread pool, or
+    /// asynchronously.
+    ///
+    /// The returned future is not `Send`, since the callbacks configured on the builder need not
+    /// be `Send`.
+    #[cfg(feature = "tokio")]
+    pub async fn build_from_source_async(mut self, src_dir: &Path) -> Result<SysrootStatus> {
+        use tokio::task::spawn_blocking;
+        let probe = self.source_probe(src_dir);
+        let sources = spawn_blocking(move || probe.run()).await??;
+        let Some(pending) = self.check_cache(sources)? else {
+            return Ok(SysrootStatus::AlreadyCached);
+        };
+        let probe = self.target_probe();
+        spawn_blocking(move || probe.run()).await??;
+        let sysroot_dir = self.sysroot_dir.clone();
+        let sysroot_lock = spawn_blocking(move || lock_dir(&sysroot_dir)).await??;
+        let Some(mut build) = self.prepare_build(pending, sysroot_lock)? else {
+            return Ok(SysrootStatus::AlreadyCached);
+        };
+        let workspace = self.workspace(&build.src_dir);
+        let build_dir = build.build_dir.clone();
+        let result = spawn_blocking(move || workspace.write(&build_dir)).await?;
+        build.keep_if_failed(result, self.should_keep_build_dir())?;
+        let result = self.run_build_async(&mut build).await;
+        Ok(build
+            .keep_if_failed(result, self.should_keep_build_dir())?
+            .status)
+    }
+
+    /// Build the `self` sysroot from the standard library sources that ship with rustc (i.e., the
+    /// `rust-src` component of the toolchain). This uses the same rustc as the rest of the build
+    /// (`$RUSTC`, or `rustc` from the `PATH`).
+    pub fn build_from_rustc_src(self) -> Result<SysrootStatus> {
+        let src_dir = rustc_sysroot_src(self.rustc_command())?;
+        if !src_dir.join("std").join("Cargo.toml").exists()
+            || !src_dir.join("core").join("Cargo.toml").exists()
+        {
             bail!(
-                "{:?} does not seem to be a rust library source folder: `src/Cargo.toml` not found",
-                src_dir
+                "the standard library sources were not found at {}; \
+                if you are using rustup, run `rustup component add rust-src` to install them",
+                src_dir.display()
             );
         }
-        let sysroot_target_dir = self.sysroot_target_dir();
-        let target_name = self.target_name().to_owned();
-        let cargo = self.cargo.take().unwrap_or_else(|| {
-            Command::new(env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo")))
-        });
-        let rustc_version = match self.rustc_version.take() {
-            Some(v) => v,
-            None => rustc_version::version_meta()?,
+        self.build_from_source(&src_dir)
+    }
+
+    /// Build the `self` sysroot from the given sources for each of the given targets (instead of
+    /// the target passed to [`SysrootBuilder::new`]), one after the other. All targets are
+    /// installed into the same sysroot directory, and each has its own cache.
+    ///
+    /// The `when_build_required` hook is called at most once, before the first target that is not
+    /// cached. The cargo command is copied for each target; only its program, arguments,
+    /// environment variables and working directory are preserved.
+    pub fn build_from_source_for_targets(
+        mut self,
+        src_dir: &Path,
+        targets: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Result<Vec<SysrootStatus>> {
+        let cargo = self.cargo.take();
+        let mut statuses = Vec::new();
+        for target in targets {
+            self.target = target.into();
+            self.cargo = cargo.as_ref().map(clone_command);
+            statuses.push(self.build_target_from_source(src_dir)?.status);
+        }
+        Ok(statuses)
+    }
+
+    /// Build the sysroot for the currently configured target. This consumes some of the builder's
+    /// state (the cargo command and the `when_build_required` hook).
+    fn build_target_from_source(&mut self, src_dir: &Path) -> Result<BuildReport> {
+        let sources = self.source_probe(src_dir).run()?;
+        let Some(pending) = self.check_cache(sources)? else {
+            return Ok(BuildReport::cached());
+        };
+        self.target_probe().run()?;
+        // Make sure nobody else is building a sysroot in the same directory at the same time. The
+        // lock is released when the build is dropped, i.e., when we return (even on errors).
+        let sysroot_lock = lock_dir(&self.sysroot_dir)?;
+        let Some(mut build) = self.prepare_build(pending, sysroot_lock)? else {
+            return Ok(BuildReport::cached());
         };
+        let result = self.workspace(&build.src_dir).write(&build.build_dir);
+        build.keep_if_failed(result, self.should_keep_build_dir())?;
+        let result = self.run_build(&mut build);
+        build.keep_if_failed(result, self.should_keep_build_dir())
+    }
+
+    /// Runs cargo for the prepared build, and installs the result.
+    fn run_build(&mut self, build: &mut PreparedBuild) -> Result<BuildReport> {
+        self.cargo_started(&build.cmd);
+        let cargo_start = Instant::now();
+        let output = output_with_timeout(&mut build.cmd, self.timeout)
+            .context("failed to execute cargo for sysroot build")?;
+        let (output, duration) = self.cargo_finished(&build.cmd, output, cargo_start)?;
+        let artifacts_installed = self.install_plan(build, &output)?.run()?;
+        Ok(self.installed(duration, artifacts_installed))
+    }
+
+    /// Like [`SysrootBuilder::run_build`], but without blocking the async runtime.
+    #[cfg(feature = "tokio")]
+    async fn run_build_async(&mut self, build: &mut PreparedBuild) -> Result<BuildReport> {
+        self.cargo_started(&build.cmd);
+        let cargo_start = Instant::now();
+        let output = output_with_timeout_async(&build.cmd, self.timeout)
+            .await
+            .context("failed to execute cargo for sysroot build")?;
+        let (output, duration) = self.cargo_finished(&build.cmd, output, cargo_start)?;
+        let plan = self.install_plan(build, &output)?;
+        let artifacts_installed = tokio::task::spawn_blocking(move || plan.run()).await??;
+        Ok(self.installed(duration, artifacts_installed))
+    }
+
+    /// Checks whether the sysroot for the currently configured target has to be built from the
+    /// given sources. If not, this reports that the sysroot is cached, and returns `None`.
+    fn check_cache(&mut self, sources: ProbedSources) -> Result<Option<PendingBuild>> {
+        let ProbedSources {
+            src_dir,
+            rustc_version,
+            hasher,
+        } = sources;
+        self.check_settings()?;
+        self.check_src_dir(&src_dir)?;
+        if (self.locked || self.frozen) && find_lock_file(&src_dir).is_none() {
+            return Err(BuildError::MissingLockfile { src_dir }.into());
+        }
 
         // Check if we even need to do anything.
-        let cur_hash = self.sysroot_compute_hash(src_dir, &rustc_version)?;
-        if self.sysroot_read_hash() == Some(cur_hash) {
+        let cur_hash = self.sysroot_finish_hash(hasher, &src_dir, &rustc_version)?;
+        debug!(
+            "sysroot hash for {}: computed {cur_hash}, stored {:?}",
+            self.target_dir().display(),
+            self.sysroot_read_hash()
+        );
+        if self.is_cached(cur_hash) {
             // Already done!
-            return Ok(SysrootStatus::AlreadyCached);
+            self.report(BuildPhase::AlreadyCached);
+            return Ok(None);
+        }
+        if self.verbosity != Verbosity::Quiet {
+            if let Some(reason) = self.rebuild_reason_for(cur_hash, &src_dir, &rustc_version)? {
+                info!(
+                    "rebuilding sysroot in {}: {reason}",
+                    self.target_dir().display()
+                );
+            }
+        }
+        Ok(Some(PendingBuild {
+            src_dir,
+            cur_hash,
+            rustc_version,
+        }))
+    }
+
+    /// Prepares the directory for the cargo workspace that builds the sysroot, once we hold the
+    /// lock on the sysroot dir. Returns `None` if the sysroot turns out to be cached after all.
+    fn prepare_build(
+        &mut self,
+        pending: PendingBuild,
+        sysroot_lock: fs::File,
+    ) -> Result<Option<PreparedBuild>> {
+        // Someone else might have built this sysroot while we waited for the lock.
+        if self.is_cached(pending.cur_hash) {
+            self.report(BuildPhase::AlreadyCached);
+            return Ok(None);
         }
 
         // A build is required, so we run the when-build-required function if one was set.
@@ -444,111 +2801,568 @@ compiler_builtins = {{ git = "https://github.com/LucasSte/compiler-builtins", ta
             when_build_required();
         }
 
-        // Prepare a workspace for cargo
-        let build_dir = TempDir::new().context("failed to create tempdir")?;
-        // Cargo.lock
-        let lock_file = build_dir.path().join("Cargo.lock");
-        let lock_file_src = {
-            // Since <https://github.com/rust-lang/rust/pull/128534>, the lock file
-            // lives inside the src_dir.
-            let new_lock_file_name = src_dir.join("Cargo.lock");
-            if new_lock_file_name.exists() {
-                new_lock_file_name
-            } else {
-                // Previously, the lock file lived one folder up.
-                src_dir
-                    .parent()
-                    .expect("src_dir must have a parent")
-                    .join("Cargo.lock")
+        // Prepare a directory for the cargo workspace.
+        let (tmp_build_dir, build_dir) = match &self.build_dir {
+            Some(build_dir) => {
+                fs::create_dir_all(build_dir).context("failed to create build dir")?;
+                (None, build_dir.clone())
+            }
+            None => {
+                let tmp_build_dir = match &self.build_dir_base {
+                    Some(base) => {
+                        fs::create_dir_all(base).context("failed to create build dir base")?;
+                        TempDir::new_in(base)
+                    }
+                    None => TempDir::new(),
+                }
+                .context("failed to create tempdir")?;
+                let build_dir = tmp_build_dir.path().to_owned();
+                (Some(tmp_build_dir), build_dir)
             }
         };
-        fs::copy(lock_file_src, &lock_file)
-            .context("failed to copy lockfile from sysroot source")?;
-        make_writeable(&lock_file).context("failed to make lockfile writeable")?;
-        // Cargo.toml
-        let manifest_file = build_dir.path().join("Cargo.toml");
-        let manifest = self.gen_manifest(src_dir);
-        fs::write(&manifest_file, manifest.as_bytes()).context("failed to write manifest file")?;
-        // lib.rs
-        let lib_file = build_dir.path().join("lib.rs");
+        let hash_file =
+            self.hash_file_contents(pending.cur_hash, &pending.src_dir, &pending.rustc_version);
+        let cargo = self.cargo.take().unwrap_or_else(Self::default_cargo_cmd);
+        let cmd = self.cargo_command(cargo, &build_dir);
+        Ok(Some(PreparedBuild {
+            _sysroot_lock: sysroot_lock,
+            tmp_build_dir,
+            build_dir,
+            src_dir: pending.src_dir,
+            hash_file,
+            cmd,
+        }))
+    }
+
+    /// Returns whether to keep the temporary build directory if the build fails.
+    fn should_keep_build_dir(&self) -> bool {
+        self.keep_build_dir || env::var_os(KEEP_BUILD_DIR_ENV).is_some()
+    }
+
+    /// Determines the cargo workspace for building the sysroot from the given sources.
+    fn workspace(&self, src_dir: &Path) -> Workspace {
         let lib = match self.config {
-            SysrootConfig::NoStd => r#"#![no_std]"#,
+            SysrootConfig::CoreOnly | SysrootConfig::NoStd => r#"#![no_std]"#,
             SysrootConfig::WithStd { .. } => "",
         };
-        fs::write(&lib_file, lib.as_bytes()).context("failed to write lib file")?;
+        Workspace {
+            lock_file_src: find_lock_file(src_dir),
+            manifest: self.gen_manifest(src_dir),
+            lib,
+        }
+    }
 
-        // Run cargo.
-        let mut cmd = cargo;
-        cmd.arg(self.mode.as_str());
-        cmd.arg("--profile");
-        cmd.arg(DEFAULT_SYSROOT_PROFILE);
-        cmd.arg("--manifest-path");
-        cmd.arg(&manifest_file);
-        cmd.arg("--target");
-        cmd.arg(&self.target);
-        // Set rustflags.
-        cmd.env("CARGO_ENCODED_RUSTFLAGS", encode_rustflags(&self.rustflags));
-        // Make sure the results end up where we expect them.
-        let build_target_dir = build_dir.path().join("target");
-        cmd.env("CARGO_TARGET_DIR", &build_target_dir);
-        // To avoid metadata conflicts, we need to inject some custom data into the crate hash.
-        // bootstrap does the same at
-        // <https://github.com/rust-lang/rust/blob/c8e12cc8bf0de646234524924f39c85d9f3c7c37/src/bootstrap/builder.rs#L1613>.
-        cmd.env("__CARGO_DEFAULT_LIB_METADATA", "rustc-build-sysroot");
+    /// Logs and reports that cargo is about to run.
+    fn cargo_started(&mut self, cmd: &Command) {
+        if self.verbosity != Verbosity::Quiet {
+            info!("building sysroot: {cmd:?}");
+        }
+        self.report(BuildPhase::CargoStarted);
+    }
 
-        let output = cmd
-            .output()
-            .context("failed to execute cargo for sysroot build")?;
+    /// Checks the outcome of running cargo (`None` meaning that it timed out), and reports that
+    /// cargo is done. Returns cargo's output and how long it took.
+    fn cargo_finished(
+        &mut self,
+        cmd: &Command,
+        output: Option<Output>,
+        cargo_start: Instant,
+    ) -> Result<(Output, Duration)> {
+        let output = output.ok_or(BuildError::Timeout {
+            timeout: self.timeout.unwrap_or_default(),
+        })?;
         if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            if stderr.is_empty() {
-                bail!("sysroot build failed");
+            return Err(BuildError::CargoFailed {
+                command: render_command(cmd),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+            .into());
+        }
+        let duration = cargo_start.elapsed();
+        self.report(BuildPhase::CargoFinished);
+        Ok((output, duration))
+    }
+
+    /// Determines what needs to be installed after cargo successfully built the sysroot.
+    fn install_plan(&mut self, build: &PreparedBuild, output: &Output) -> Result<InstallPlan> {
+        self.report(BuildPhase::Staging);
+        // TempDir expects the parent to already exist. The sysroot dir was created when we locked
+        // it.
+        let staging_parent = match &self.staging_dir {
+            Some(staging_dir) => {
+                fs::create_dir_all(staging_dir).context("failed to create staging dir")?;
+                staging_dir.clone()
+            }
+            None => self.sysroot_dir.clone(),
+        };
+        // Artifacts for build script dependencies are built for the host; we only want the ones
+        // built for the target.
+        let target_out_dir = build.build_dir.join("target").join(self.target_name());
+        let contents = if self.mode == BuildMode::Doc {
+            // The docs are not reported as artifacts; cargo puts them all in one directory.
+            InstallContents::Docs(target_out_dir.join("doc"))
+        } else {
+            let extensions = self.artifact_extensions_or_default();
+            let artifacts = cargo_artifacts(&output.stdout)?
+                .into_iter()
+                .filter(|artifact| artifact.starts_with(&target_out_dir))
+                // Cargo can also report directories (like `.dSYM` bundles on macOS), which are not
+                // needed in a sysroot.
+                .filter(|artifact| artifact.is_file())
+                .filter(|artifact| {
+                    artifact
+                        .extension()
+                        .and_then(OsStr::to_str)
+                        .is_some_and(|extension| extensions.contains(&extension))
+                })
+                .collect::<Vec<_>>();
+            // Installing nothing would leave us with a sysroot that looks up-to-date but is
+            // useless.
+            if artifacts.is_empty() {
+                bail!(
+                    "cargo did not report any artifacts for {:?} in {}",
+                    self.target,
+                    target_out_dir.display()
+                );
+            }
+            InstallContents::Artifacts(artifacts)
+        };
+        let sources = self.install_sources.then(|| {
+            let sources_dir = self
+                .sysroot_dir
+                .join("lib")
+                .join("rustlib")
+                .join("src")
+                .join("rust")
+                .join("library");
+            (build.src_dir.clone(), sources_dir)
+        });
+        Ok(InstallPlan {
+            staging_parent,
+            contents,
+            hardlink_artifacts: self.hardlink_artifacts,
+            hash_file_path: self.hash_file_path(),
+            hash_file: self.write_hash.then(|| build.hash_file.clone()),
+            target_dir: self.target_dir(),
+            library_extensions: self
+                .library_extensions()
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+            incremental: self.incremental_install,
+            sources,
+        })
+    }
+
+    /// Logs and reports that the sysroot was installed.
+    fn installed(&mut self, duration: Duration, artifacts_installed: usize) -> BuildReport {
+        if self.verbosity != Verbosity::Quiet {
+            info!("installed sysroot to {}", self.target_dir().display());
+        }
+        self.report(BuildPhase::Installed);
+        BuildReport {
+            status: SysrootStatus::SysrootBuilt,
+            duration,
+            artifacts_installed,
+        }
+    }
+}
+
+/// The inputs of a sysroot build that turned out to be necessary.
+struct PendingBuild {
+    src_dir: PathBuf,
+    cur_hash: u64,
+    rustc_version: rustc_version::VersionMeta,
+}
+
+/// A sysroot build whose cargo workspace is ready.
+struct PreparedBuild {
+    /// The lock on the sysroot dir, held for the entire build.
+    _sysroot_lock: fs::File,
+    /// The temporary directory that `build_dir` is, unless a persistent one was configured.
+    tmp_build_dir: Option<TempDir>,
+    build_dir: PathBuf,
+    src_dir: PathBuf,
+    /// The contents of the hash file to install.
+    hash_file: String,
+    /// The cargo invocation that builds the sysroot.
+    cmd: Command,
+}
+
+impl PreparedBuild {
+    /// Passes on the given result. If it is an error and `keep_build_dir` is set, the temporary
+    /// build directory is kept (and mentioned in the error).
+    fn keep_if_failed<T>(&mut self, result: Result<T>, keep_build_dir: bool) -> Result<T> {
+        match result {
+            Err(err) if keep_build_dir && self.tmp_build_dir.is_some() => {
+                let build_dir = self.tmp_build_dir.take().unwrap().keep();
+                Err(err.context(format!(
+                    "sysroot build failed; the build directory was kept at {}",
+                    build_dir.display()
+                )))
+            }
+            result => result,
+        }
+    }
+}
+
+/// Everything needed to look at the sources and the toolchain before we can tell whether a sysroot
+/// is cached. Doing so reads the entire source tree and runs rustc; this does not borrow from the
+/// builder, so that it can be run on another thread.
+struct SourceProbe {
+    /// The sources as given by the user.
+    src_dir: PathBuf,
+    rustc: Command,
+    /// The configured rustc version, if any.
+    rustc_version: Option<rustc_version::VersionMeta>,
+    /// The configured toolchain, if we need to check that it matches the configured rustc version.
+    toolchain: Option<String>,
+}
+
+/// What [`SourceProbe`] found out.
+struct ProbedSources {
+    /// The `library` dir of the sources.
+    src_dir: PathBuf,
+    rustc_version: rustc_version::VersionMeta,
+    /// The sysroot hash so far (see [`hash_sources`]).
+    hasher: DefaultHasher,
+}
+
+impl SourceProbe {
+    fn run(self) -> Result<ProbedSources> {
+        let src_dir = find_library_dir(&self.src_dir)?;
+        let rustc_version = match &self.rustc_version {
+            Some(v) => v.clone(),
+            None => rustc_version::VersionMeta::for_command(clone_command(&self.rustc))?,
+        };
+        self.check_toolchain_version(&rustc_version);
+        let hasher = hash_sources(&src_dir)?;
+        Ok(ProbedSources {
+            src_dir,
+            rustc_version,
+            hasher,
+        })
+    }
+
+    /// Warns if the configured rustc version does not match the configured toolchain. This is
+    /// best-effort: if we cannot determine the toolchain's version, we stay silent.
+    fn check_toolchain_version(&self, rustc_version: &rustc_version::VersionMeta) {
+        let Some(toolchain) = &self.toolchain else {
+            return;
+        };
+        let Ok(toolchain_version) =
+            rustc_version::VersionMeta::for_command(clone_command(&self.rustc))
+        else {
+            return;
+        };
+        if toolchain_version.semver != rustc_version.semver
+            || toolchain_version.commit_hash != rustc_version.commit_hash
+        {
+            warn!(
+                "toolchain {toolchain} has rustc version {}, but the sysroot is configured for {}",
+                toolchain_version.short_version_string, rustc_version.short_version_string,
+            );
+        }
+    }
+}
+
+/// The checks of the configured target that involve running rustc, which we only do once we know
+/// that we have to build. This does not borrow from the builder, so that it can be run on another
+/// thread.
+struct TargetProbe {
+    rustc: Command,
+    target: OsString,
+    /// See [`SysrootBuilder::target_name`].
+    target_name: OsString,
+    /// See [`SysrootBuilder::target_spec_file`].
+    target_spec_file: Option<PathBuf>,
+    /// Whether the sysroot includes `std`.
+    with_std: bool,
+}
+
+impl TargetProbe {
+    fn run(self) -> Result<()> {
+        self.check_target()?;
+        // Building std for a target without an OS is guaranteed to fail, and with a rather
+        // confusing error deep inside std, so we detect that up-front.
+        if self.with_std && !self.target_supports_std() {
+            return Err(BuildError::StdNotSupported {
+                target: self.target,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Makes sure the target exists: either it is a target specification file, or a target that
+    /// rustc knows about (built-in, or found via `RUST_TARGET_PATH`). If we cannot get the list of
+    /// built-in targets, we give rustc the benefit of the doubt.
+    fn check_target(&self) -> Result<()> {
+        let unknown_target = || BuildError::UnknownTarget {
+            target: self.target.clone(),
+        };
+        if let Some(target_spec_file) = &self.target_spec_file {
+            return if target_spec_file.is_file() {
+                Ok(())
             } else {
-                bail!("sysroot build failed; stderr:\n{}", stderr);
+                Err(unknown_target().into())
+            };
+        }
+        let output = clone_command(&self.rustc)
+            .args(["--print", "target-list"])
+            .output();
+        let output = match output {
+            Ok(output) if output.status.success() => output,
+            _ => return Ok(()),
+        };
+        let builtin = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| OsStr::new(line) == self.target);
+        let custom = || {
+            let mut file_name = self.target.clone();
+            file_name.push(".json");
+            env::var_os("RUST_TARGET_PATH").is_some_and(|paths| {
+                env::split_paths(&paths).any(|path| path.join(&file_name).is_file())
+            })
+        };
+        if builtin || custom() {
+            Ok(())
+        } else {
+            Err(unknown_target().into())
+        }
+    }
+
+    /// Returns whether the target supports `std`. This is a best-effort check: if we cannot find
+    /// out, we assume that it does.
+    fn target_supports_std(&self) -> bool {
+        // Operating systems for which there is no `std`.
+        const NO_STD_OS: &[&str] = &["none", "cuda", "amdhsa"];
+        let output = clone_command(&self.rustc)
+            .args(["--print", "cfg", "--target"])
+            .arg(&self.target)
+            .output();
+        match output {
+            Ok(output) if output.status.success() => !String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|line| line.strip_prefix("target_os="))
+                .any(|os| NO_STD_OS.contains(&os.trim_matches('"'))),
+            // If rustc cannot tell us, go by the name: bare-metal targets have `none` as their OS
+            // component (like `riscv32imac-unknown-none-elf` or `thumbv7em-none-eabihf`).
+            _ => !self
+                .target_name
+                .to_string_lossy()
+                .split('-')
+                .any(|component| component == "none"),
+        }
+    }
+}
+
+/// The files of the cargo workspace that builds the sysroot. This does not borrow from the
+/// builder, so that it can be written on another thread.
+struct Workspace {
+    /// The lock file to start from, if any.
+    lock_file_src: Option<PathBuf>,
+    manifest: String,
+    lib: &'static str,
+}
+
+impl Workspace {
+    /// Writes the workspace into `build_dir`.
+    fn write(self, build_dir: &Path) -> Result<()> {
+        // Cargo.lock
+        let lock_file = build_dir.join("Cargo.lock");
+        // Without a lock file, cargo will resolve the dependencies and generate one itself.
+        if let Some(lock_file_src) = &self.lock_file_src {
+            // In a persistent build dir, we keep the lock file of the previous build (which cargo
+            // has already resolved) if the lock file in the sources did not change. To tell, we
+            // keep a pristine copy of the latter.
+            let lock_file_orig = build_dir.join("Cargo.lock.orig");
+            if !lock_file.exists() || !same_contents(lock_file_src, &lock_file_orig)? {
+                fs::copy(lock_file_src, &lock_file)
+                    .context("failed to copy lockfile from sysroot source")?;
+                make_writeable(&lock_file).context("failed to make lockfile writeable")?;
+                fs::copy(lock_file_src, &lock_file_orig)
+                    .context("failed to copy lockfile from sysroot source")?;
+                make_writeable(&lock_file_orig).context("failed to make lockfile writeable")?;
+            } else {
+                debug!("reusing lockfile in {}", build_dir.display());
             }
         }
+        // Cargo.toml
+        let manifest_file = build_dir.join("Cargo.toml");
+        fs::write(&manifest_file, self.manifest.as_bytes())
+            .context("failed to write manifest file")?;
+        debug!("wrote sysroot manifest to {}", manifest_file.display());
+        // lib.rs
+        let lib_file = build_dir.join("lib.rs");
+        fs::write(&lib_file, self.lib.as_bytes()).context("failed to write lib file")?;
+        Ok(())
+    }
+}
+
+/// What gets installed into the sysroot.
+enum InstallContents {
+    /// The given library files, to be installed into `lib`.
+    Artifacts(Vec<PathBuf>),
+    /// The given documentation directory, to be installed as `doc`.
+    Docs(PathBuf),
+}
+
+/// Everything needed to install a freshly built sysroot. This does not borrow from the builder,
+/// so that it can be run on another thread.
+struct InstallPlan {
+    /// Where to create the staging dir.
+    staging_parent: PathBuf,
+    contents: InstallContents,
+    hardlink_artifacts: bool,
+    /// Where the hash file goes, relative to the target dir.
+    hash_file_path: PathBuf,
+    /// The contents of the hash file, unless no hash file should be written.
+    hash_file: Option<String>,
+    /// Where to install the sysroot for the target.
+    target_dir: PathBuf,
+    /// The extensions of the library files in the installed sysroot that get replaced by the new
+    /// ones.
+    library_extensions: Vec<String>,
+    incremental: bool,
+    /// The library sources and where to install them, if requested.
+    sources: Option<(PathBuf, PathBuf)>,
+}
+
+impl InstallPlan {
+    /// Returns whether the given file or directory (relative to the target dir) of the installed
+    /// sysroot gets replaced by the new sysroot.
+    fn is_replaced(&self, relative: &Path) -> bool {
+        let is_library = relative.parent() == Some(Path::new("lib"))
+            && relative
+                .extension()
+                .and_then(OsStr::to_str)
+                .is_some_and(|extension| self.library_extensions.iter().any(|e| e == extension));
+        match self.contents {
+            // The docs come with their own hash file.
+            InstallContents::Docs(_) => relative == Path::new("doc"),
+            InstallContents::Artifacts(_) => is_library || relative == self.hash_file_path,
+        }
+    }
+
+    /// Assembles the sysroot in a staging dir and installs it. Returns how many library files
+    /// were installed.
+    fn run(self) -> Result<usize> {
+        let sysroot_target_dir = &self.target_dir;
+        // Installing incrementally into a sysroot that links to another one (see
+        // `SysrootBuilder::link_target_dir`) would modify the latter, so we replace the link
+        // instead.
+        let incremental = self.incremental && !sysroot_target_dir.is_symlink();
 
         // Create a staging dir that will become the target sysroot dir (so that we can do the final
         // installation atomically).
-        fs::create_dir_all(&self.sysroot_dir).context("failed to create sysroot dir")?; // TempDir expects the parent to already exist
         let staging_dir =
-            TempDir::new_in(&self.sysroot_dir).context("failed to create staging dir")?;
+            TempDir::new_in(&self.staging_parent).context("failed to create staging dir")?;
+
+        // Whatever else is in the target dir has to survive the installation. If the sysroot
+        // shadows a toolchain sysroot (see `create_shadow_sysroot`), that includes tools like
+        // `rust-lld` in `bin`, and the `self-contained` objects in `lib`. So we start from a copy of
+        // the installed sysroot without the parts that we are about to replace: the libraries and
+        // the hash file, or the docs. (Those cannot be copied anyway: overwriting a hard link would
+        // modify the installed file.)
+        if sysroot_target_dir.is_dir() {
+            mirror_dir(sysroot_target_dir, staging_dir.path(), |relative| {
+                self.is_replaced(relative)
+            })
+            .context("failed to copy the installed sysroot to the staging dir")?;
+        }
         // Copy the output to `$staging/lib`.
         let staging_lib_dir = staging_dir.path().join("lib");
-        fs::create_dir(&staging_lib_dir).context("faiked to create staging/lib dir")?;
-        let out_dir = build_target_dir
-            .join(&target_name)
-            .join(DEFAULT_SYSROOT_PROFILE)
-            .join("deps");
-        for entry in fs::read_dir(&out_dir).context("failed to read cargo out dir")? {
-            let entry = entry.context("failed to read cargo out dir entry")?;
-            assert!(
-                entry.file_type().unwrap().is_file(),
-                "cargo out dir must not contain directories"
-            );
-            let entry = entry.path();
-            fs::copy(&entry, staging_lib_dir.join(entry.file_name().unwrap()))
-                .context("failed to copy cargo out file")?;
-        }
-
-        // Write the hash file (into the staging dir).
-        fs::write(
-            staging_dir.path().join(HASH_FILE_NAME),
-            cur_hash.to_string().as_bytes(),
-        )
-        .context("failed to write hash file")?;
-
-        // Atomic copy to final destination via rename.
-        if sysroot_target_dir.exists() {
-            // Remove potentially outdated files.
-            fs::remove_dir_all(&sysroot_target_dir)
-                .context("failed to clean sysroot target dir")?;
-        }
-        // Create the *parent* directroy so we can move into it.
-        fs::create_dir_all(&sysroot_target_dir.parent().unwrap())
-            .context("failed to create target directory")?;
-        fs::rename(staging_dir.path(), sysroot_target_dir).context("failed installing sysroot")?;
-
-        Ok(SysrootStatus::SysrootBuilt)
+        fs::create_dir_all(&staging_lib_dir).context("faiked to create staging/lib dir")?;
+        // The artifacts that are already installed, when installing incrementally.
+        let mut unchanged = Vec::new();
+        let artifacts_installed = match &self.contents {
+            InstallContents::Docs(doc_dir) => {
+                copy_dir_recursive(doc_dir, &staging_dir.path().join("doc"))
+                    .context("failed to copy sysroot docs to the staging dir")?;
+                debug!("copied docs from {} to the staging dir", doc_dir.display());
+                0
+            }
+            InstallContents::Artifacts(artifacts) => {
+                // When installing incrementally, there is no need to stage the artifacts that are
+                // already installed.
+                let mut changed = Vec::new();
+                for artifact in artifacts {
+                    let relative = Path::new("lib").join(artifact.file_name().unwrap());
+                    if incremental && same_contents(artifact, &sysroot_target_dir.join(&relative))?
+                    {
+                        unchanged.push(relative);
+                    } else {
+                        changed.push(artifact.clone());
+                    }
+                }
+                copy_files(&changed, &staging_lib_dir, self.hardlink_artifacts)?;
+                debug!("copied {} artifacts to the staging dir", changed.len());
+                artifacts.len()
+            }
+        };
+
+        // Write the hash file (into the staging dir). Since the staging dir is installed
+        // atomically, the hash file can never be observed half-written.
+        if let Some(hash_file) = &self.hash_file {
+            fs::write(
+                staging_dir.path().join(&self.hash_file_path),
+                hash_file.as_bytes(),
+            )
+            .context("failed to write hash file")?;
+        }
+
+        if incremental {
+            install_incrementally(
+                staging_dir.path(),
+                sysroot_target_dir,
+                &self.hash_file_path,
+                &unchanged,
+            )
+            .context(BuildError::InstallFailed)?;
+        } else {
+            // Atomic copy to final destination via rename. We move the old sysroot out of the
+            // way rather than deleting it, and only delete it once the new one is in place, so
+            // that there is a working sysroot at all times (except for the instant between the
+            // two renames).
+            let backup_dir = {
+                let mut backup_name = sysroot_target_dir.file_name().unwrap().to_owned();
+                backup_name.push(".old");
+                sysroot_target_dir.with_file_name(backup_name)
+            };
+            if backup_dir.exists() {
+                // Left behind by an earlier, interrupted installation.
+                fs::remove_dir_all(&backup_dir).context("failed to remove stale sysroot backup")?;
+            }
+            let have_backup = sysroot_target_dir.exists();
+            if have_backup {
+                fs::rename(sysroot_target_dir, &backup_dir)
+                    .context("failed to move old sysroot out of the way")
+                    .context(BuildError::InstallFailed)?;
+            }
+            // Create the *parent* directroy so we can move into it.
+            fs::create_dir_all(sysroot_target_dir.parent().unwrap())
+                .context("failed to create target directory")?;
+            if let Err(err) = move_dir(staging_dir.path(), sysroot_target_dir) {
+                if have_backup {
+                    // Put the old sysroot back. If even that fails, the original error is more
+                    // useful.
+                    let _ = fs::rename(&backup_dir, sysroot_target_dir);
+                }
+                return Err(err.context(BuildError::InstallFailed));
+            }
+            if have_backup {
+                fs::remove_dir_all(&backup_dir).context("failed to remove old sysroot")?;
+            }
+        }
+        if let Some((src_dir, sources_dir)) = &self.sources {
+            // This makes the sources available like the `rust-src` component does for a regular
+            // sysroot.
+            link_dir(src_dir, sources_dir)
+                .context("failed to install sysroot sources")
+                .context(BuildError::InstallFailed)?;
+        }
+        Ok(artifacts_installed)
     }
 }
@@ -1,5 +1,8 @@
+use std::ffi::{OsStr, OsString};
 use std::fs;
+use std::path::Path;
 use std::process::{self, Command};
+use std::time::Duration;
 
 use rustc_version::VersionMeta;
 use tempfile::tempdir;
@@ -15,6 +18,88 @@ fn run(cmd: &mut Command) {
         .success());
 }
 
+/// Creates a source dir with (empty) manifests for the given crates, for tests that should fail
+/// before cargo gets involved.
+fn fake_src_dir(crates: &[&str]) -> tempfile::TempDir {
+    let src_dir = tempdir().unwrap();
+    for krate in crates {
+        fs::create_dir(src_dir.path().join(krate)).unwrap();
+        fs::write(src_dir.path().join(krate).join("Cargo.toml"), "").unwrap();
+    }
+    src_dir
+}
+
+/// Returns a "cargo" that just runs the given shell script, for tests that only care about what
+/// happens around the cargo invocation. Cargo's arguments are the positional parameters.
+#[cfg(unix)]
+fn fake_cargo(script: &str) -> Command {
+    let mut cargo = Command::new("sh");
+    cargo.args(["-c", script, "cargo"]);
+    cargo
+}
+
+/// A script for [`fake_cargo`] that pretends to build a sysroot with std: it creates (empty)
+/// libraries in cargo's target dir and reports them like cargo does.
+#[cfg(unix)]
+const FAKE_CARGO_BUILD: &str = r#"
+while [ $# -gt 0 ]; do
+    if [ "$1" = --target ]; then target=$2; fi
+    shift
+done
+case $CARGO_TARGET_DIR in /*) ;; *) CARGO_TARGET_DIR=$PWD/$CARGO_TARGET_DIR ;; esac
+out=$CARGO_TARGET_DIR/$target/custom_sysroot/deps
+mkdir -p "$out"
+for krate in core alloc std; do
+    touch "$out/lib$krate-fake.rlib"
+    echo "{\"reason\":\"compiler-artifact\",\"filenames\":[\"$out/lib$krate-fake.rlib\"]}"
+done
+"#;
+
+/// A sysroot dir with fake std sources to build it from, for tests that do not need the real
+/// sources.
+struct FakeSysroot {
+    rustc_version: VersionMeta,
+    src_dir: tempfile::TempDir,
+    sysroot_dir: tempfile::TempDir,
+}
+
+impl FakeSysroot {
+    fn new() -> Self {
+        FakeSysroot {
+            rustc_version: VersionMeta::for_command(Command::new("rustc")).unwrap(),
+            src_dir: fake_src_dir(&["core", "alloc", "std", "sysroot"]),
+            sysroot_dir: tempdir().unwrap(),
+        }
+    }
+
+    fn src_dir(&self) -> &Path {
+        self.src_dir.path()
+    }
+
+    fn sysroot_dir(&self) -> &Path {
+        self.sysroot_dir.path()
+    }
+
+    /// Returns a builder for a host sysroot in the sysroot dir.
+    fn builder(&self) -> SysrootBuilder<'_> {
+        SysrootBuilder::for_host(self.sysroot_dir(), self.rustc_version.clone())
+    }
+
+    /// Like [`FakeSysroot::builder`], but cargo only pretends to build the sysroot.
+    #[cfg(unix)]
+    fn fake_builder(&self) -> SysrootBuilder<'_> {
+        self.builder().cargo(fake_cargo(FAKE_CARGO_BUILD))
+    }
+}
+
+/// Returns the [`BuildError`] that the given build failed with.
+fn build_error<T: std::fmt::Debug>(result: anyhow::Result<T>) -> BuildError {
+    match result.unwrap_err().downcast::<BuildError>() {
+        Ok(err) => err,
+        Err(err) => panic!("unexpected error: {:?}", err),
+    }
+}
+
 fn build_sysroot(b: SysrootBuilder) {
     let src_dir = rustc_sysroot_src(Command::new("rustc")).unwrap();
     b.cargo(Command::new("cargo"))
@@ -79,6 +164,142 @@ fn cross() {
     }
 }
 
+#[test]
+fn multiple_targets() {
+    let sysroot_dir = tempdir().unwrap();
+    let src_dir = rustc_sysroot_src(Command::new("rustc")).unwrap();
+    let targets = ["thumbv7em-none-eabihf", "riscv32imac-unknown-none-elf"];
+    let statuses = SysrootBuilder::new(sysroot_dir.path(), targets[0])
+        .build_mode(BuildMode::Check)
+        .sysroot_config(SysrootConfig::NoStd)
+        .cargo(Command::new("cargo"))
+        .build_from_source_for_targets(&src_dir, targets)
+        .unwrap();
+    assert_eq!(statuses, [SysrootStatus::SysrootBuilt; 2]);
+    let mut hashes = vec![];
+    for target in targets {
+        assert!(sysroot_dir
+            .path()
+            .join("lib/rustlib")
+            .join(target)
+            .join("lib")
+            .is_dir());
+        let builder = SysrootBuilder::new(sysroot_dir.path(), target)
+            .build_mode(BuildMode::Check)
+            .sysroot_config(SysrootConfig::NoStd);
+        assert!(!builder.needs_rebuild(&src_dir).unwrap());
+        hashes.push(builder.installed_hash().unwrap());
+    }
+    // Each target got its own build.
+    assert_ne!(hashes[0], hashes[1]);
+}
+
+#[test]
+fn target_hash() {
+    let rustc_version = VersionMeta::for_command(Command::new("rustc")).unwrap();
+    let src_dir = fake_src_dir(&["core", "alloc", "std"]);
+    let sysroot_dir = tempdir().unwrap();
+    let hash = |target| {
+        SysrootBuilder::new(sysroot_dir.path(), target)
+            .sysroot_config(SysrootConfig::NoStd)
+            .rustc_version(rustc_version.clone())
+            .current_hash(src_dir.path())
+            .unwrap()
+    };
+    assert_ne!(
+        hash("thumbv7em-none-eabihf"),
+        hash("riscv32imac-unknown-none-elf")
+    );
+}
+
+#[test]
+fn ensure() {
+    let rustc_version = VersionMeta::for_command(Command::new("rustc")).unwrap();
+    let sysroot_dir = tempdir().unwrap();
+    let src_dir = rustc_sysroot_src(Command::new("rustc")).unwrap();
+    let ensure = || {
+        ensure_sysroot(
+            sysroot_dir.path(),
+            &rustc_version.host,
+            &src_dir,
+            BuildMode::Check,
+            rustc_version.clone(),
+            Command::new("cargo"),
+        )
+        .unwrap()
+    };
+    let handle = ensure();
+    assert_eq!(handle.status, SysrootStatus::SysrootBuilt);
+    assert!(handle.target_dir.join("lib").is_dir());
+    assert_eq!(
+        handle.rustc_args(),
+        [OsStr::new("--sysroot"), sysroot_dir.path().as_os_str()]
+    );
+    assert_eq!(ensure().status, SysrootStatus::AlreadyCached);
+}
+
+/// Sets the permissions of everything in `dir` to read-only (or back to writable).
+fn set_readonly_recursive(dir: &Path, readonly: bool) {
+    for entry in walkdir::WalkDir::new(dir).contents_first(!readonly) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_symlink() {
+            continue;
+        }
+        let mut perms = entry.metadata().unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(readonly);
+        fs::set_permissions(entry.path(), perms).unwrap();
+    }
+}
+
+#[test]
+fn read_only_sources() {
+    let src_dir = rustc_sysroot_src(Command::new("rustc")).unwrap();
+    let read_only_dir = tempdir().unwrap();
+    let read_only_src = read_only_dir.path().join("library");
+    for entry in walkdir::WalkDir::new(&src_dir) {
+        let entry = entry.unwrap();
+        let dest = read_only_src.join(entry.path().strip_prefix(&src_dir).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(dest).unwrap();
+        } else {
+            fs::copy(entry.path(), dest).unwrap();
+        }
+    }
+    set_readonly_recursive(&read_only_src, true);
+    let sysroot_dir = tempdir().unwrap();
+    let result = SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+        .build_mode(BuildMode::Check)
+        .sysroot_config(SysrootConfig::NoStd)
+        .cargo(Command::new("cargo"))
+        .build_from_source(&read_only_src);
+    // Make sure the temporary directory can be cleaned up.
+    set_readonly_recursive(&read_only_src, false);
+    assert_eq!(result.unwrap(), SysrootStatus::SysrootBuilt);
+}
+
+#[test]
+#[cfg(unix)]
+fn read_only_lockfile() {
+    let fake = FakeSysroot::new();
+    let src_dir = fake.src_dir();
+    fs::write(src_dir.join("Cargo.lock"), "version = 3").unwrap();
+    set_readonly_recursive(src_dir, true);
+    let build_dir = tempdir().unwrap();
+    for _ in 0..2 {
+        fake.builder()
+            .cargo(fake_cargo("exit 1"))
+            .build_dir(build_dir.path())
+            .force_rebuild(true)
+            .build_from_source(src_dir)
+            .unwrap_err();
+        // Cargo needs to be able to update the lock file.
+        let lock_file = build_dir.path().join("Cargo.lock");
+        assert!(!fs::metadata(lock_file).unwrap().permissions().readonly());
+    }
+    set_readonly_recursive(src_dir, false);
+}
+
 #[test]
 fn no_std() {
     let sysroot_dir = tempdir().unwrap();
@@ -89,6 +310,48 @@ fn no_std() {
     );
 }
 
+#[test]
+fn core_only() {
+    let sysroot_dir = tempdir().unwrap();
+    build_sysroot(
+        SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+            .build_mode(BuildMode::Check)
+            .sysroot_config(SysrootConfig::CoreOnly),
+    );
+}
+
+#[test]
+fn persistent_build_dir() {
+    let sysroot_dir = tempdir().unwrap();
+    let build_dir = tempdir().unwrap();
+    let builder = || {
+        SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+            .build_mode(BuildMode::Check)
+            .sysroot_config(SysrootConfig::NoStd)
+            .build_dir(build_dir.path())
+    };
+    build_sysroot(builder());
+    // Changing the flags requires a rebuild, which can reuse the existing build dir.
+    build_sysroot(builder().rustflag("-Cdebug-assertions=on"));
+}
+
+#[test]
+fn sysroot_as_src_dir() {
+    // Passing the sysroot itself (rather than its `library` source folder) should also work.
+    let output = Command::new("rustc")
+        .args(["--print", "sysroot"])
+        .output()
+        .unwrap();
+    let rustc_sysroot = String::from_utf8(output.stdout).unwrap();
+    let sysroot_dir = tempdir().unwrap();
+    SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+        .build_mode(BuildMode::Check)
+        .sysroot_config(SysrootConfig::NoStd)
+        .cargo(Command::new("cargo"))
+        .build_from_source(Path::new(rustc_sysroot.trim_end()))
+        .unwrap();
+}
+
 #[test]
 fn json_target() {
     // Example taken from https://book.avr-rust.com/005.1-the-target-specification-json-file.html
@@ -129,3 +392,1278 @@ fn json_target() {
             .sysroot_config(SysrootConfig::NoStd),
     );
 }
+
+#[test]
+fn missing_sources() {
+    let sysroot_dir = tempdir().unwrap();
+    let src_dir = tempdir().unwrap();
+    let err = build_error(
+        SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+            .build_from_source(src_dir.path()),
+    );
+    assert!(matches!(err, BuildError::SourceNotFound { .. }));
+}
+
+#[test]
+fn unknown_target() {
+    let fake = FakeSysroot::new();
+    for target in ["thumbv7em-none-eabihff", "does-not-exist.json"] {
+        let err = build_error(
+            SysrootBuilder::new(fake.sysroot_dir(), target).build_from_source(fake.src_dir()),
+        );
+        assert!(matches!(err, BuildError::UnknownTarget { .. }));
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn cached_without_rustc() {
+    use std::os::unix::fs::PermissionsExt;
+    let fake = FakeSysroot::new();
+    // A "rustc" that logs how it was called (and then fails, which makes us assume the best).
+    let rustc_dir = tempdir().unwrap();
+    let rustc = rustc_dir.path().join("rustc");
+    let rustc_log = rustc_dir.path().join("log");
+    let script = format!(
+        "#!/bin/sh\necho \"$@\" >> '{}'\nexit 1\n",
+        rustc_log.display()
+    );
+    fs::write(&rustc, script).unwrap();
+    fs::set_permissions(&rustc, fs::Permissions::from_mode(0o755)).unwrap();
+    let build = || {
+        fake.fake_builder()
+            .rustc(&rustc)
+            .build_from_source(fake.src_dir())
+            .unwrap()
+    };
+    assert_eq!(build(), SysrootStatus::SysrootBuilt);
+    let calls = fs::read_to_string(&rustc_log).unwrap();
+    assert!(calls.contains("target-list"));
+    // When the sysroot is cached, there is no need to ask rustc about the target.
+    assert_eq!(build(), SysrootStatus::AlreadyCached);
+    assert_eq!(fs::read_to_string(&rustc_log).unwrap(), calls);
+}
+
+#[test]
+fn incomplete_sources() {
+    let src_dir = fake_src_dir(&["core", "std"]);
+    let sysroot_dir = tempdir().unwrap();
+    let err = build_error(
+        SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+            .sysroot_config(SysrootConfig::NoStd)
+            .build_from_source(src_dir.path()),
+    );
+    let BuildError::IncompleteSource { path } = err else {
+        panic!("unexpected error: {:?}", err);
+    };
+    assert_eq!(
+        path,
+        src_dir
+            .path()
+            .canonicalize()
+            .unwrap()
+            .join("alloc/Cargo.toml")
+    );
+}
+
+#[test]
+fn missing_proc_macro() {
+    let fake = FakeSysroot::new();
+    let err = build_error(
+        SysrootBuilder::new(fake.sysroot_dir(), "thumbv7em-none-eabihf")
+            .proc_macro(true)
+            .build_from_source(fake.src_dir()),
+    );
+    let BuildError::IncompleteSource { path } = err else {
+        panic!("unexpected error: {:?}", err);
+    };
+    assert_eq!(
+        path,
+        fake.src_dir()
+            .canonicalize()
+            .unwrap()
+            .join("proc_macro/Cargo.toml")
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn timeout() {
+    let fake = FakeSysroot::new();
+    // A "cargo" that ignores its arguments and never finishes.
+    let err = build_error(
+        fake.builder()
+            .cargo(fake_cargo("sleep 60"))
+            .timeout(Duration::from_millis(200))
+            .build_from_source(fake.src_dir()),
+    );
+    assert!(matches!(err, BuildError::Timeout { .. }));
+}
+
+#[test]
+#[cfg(all(unix, feature = "tokio"))]
+fn timeout_async() {
+    let fake = FakeSysroot::new();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let err = build_error(
+        runtime.block_on(
+            fake.builder()
+                .cargo(fake_cargo("sleep 60"))
+                .timeout(Duration::from_millis(200))
+                .build_from_source_async(fake.src_dir()),
+        ),
+    );
+    assert!(matches!(err, BuildError::Timeout { .. }));
+}
+
+#[test]
+#[cfg(all(unix, feature = "tokio"))]
+fn build_async() {
+    let fake = FakeSysroot::new();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let build = || {
+        runtime
+            .block_on(fake.fake_builder().build_from_source_async(fake.src_dir()))
+            .unwrap()
+    };
+    assert_eq!(build(), SysrootStatus::SysrootBuilt);
+    assert_eq!(build(), SysrootStatus::AlreadyCached);
+    assert!(fake.builder().verify().unwrap());
+}
+
+#[test]
+#[cfg(unix)]
+fn build_dir_base() {
+    let fake = FakeSysroot::new();
+    let build_dir_base = fake.sysroot_dir().join("build");
+    // A "cargo" that fails right away, so that we can see where the build dir was.
+    let err = fake
+        .builder()
+        .cargo(fake_cargo("exit 1"))
+        .build_dir_base(&build_dir_base)
+        .keep_build_dir_on_failure(true)
+        .build_from_source(fake.src_dir())
+        .unwrap_err();
+    let build_dirs: Vec<_> = fs::read_dir(&build_dir_base)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(build_dirs.len(), 1);
+    assert!(build_dirs[0].join("Cargo.toml").is_file());
+    assert!(format!("{err:#}").contains(&*build_dirs[0].to_string_lossy()));
+}
+
+/// Returns a path to `path` (which must be absolute) that is relative to the current directory.
+#[cfg(unix)]
+fn relative_path(path: &Path) -> std::path::PathBuf {
+    let cwd = std::env::current_dir().unwrap();
+    let mut relative: std::path::PathBuf = cwd.components().skip(1).map(|_| "..").collect();
+    relative.push(path.strip_prefix("/").unwrap());
+    relative
+}
+
+#[test]
+#[cfg(unix)]
+fn relative_build_dir() {
+    let fake = FakeSysroot::new();
+    let build_dir = tempdir().unwrap();
+    let report = fake
+        .fake_builder()
+        .build_dir(&relative_path(build_dir.path()))
+        .build_from_source_with_report(fake.src_dir())
+        .unwrap();
+    assert_eq!(report.artifacts_installed, 3);
+    // The same goes for the base of temporary build dirs.
+    let report = fake
+        .fake_builder()
+        .build_dir_base(&relative_path(build_dir.path()))
+        .force_rebuild(true)
+        .build_from_source_with_report(fake.src_dir())
+        .unwrap();
+    assert_eq!(report.artifacts_installed, 3);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn cross_device_install() {
+    use std::os::unix::fs::MetadataExt;
+    let fake = FakeSysroot::new();
+    // Staging on another file system means that installing cannot just rename the files. Without
+    // a separate `/dev/shm`, there is nothing to test.
+    let shm = Path::new("/dev/shm");
+    if !shm.is_dir()
+        || fs::metadata(shm).unwrap().dev() == fs::metadata(fake.sysroot_dir()).unwrap().dev()
+    {
+        return;
+    }
+    let staging_dir = tempfile::tempdir_in(shm).unwrap();
+    let builder = || {
+        fake.fake_builder()
+            .staging_dir(staging_dir.path())
+            .force_rebuild(true)
+    };
+    // Installing the entire sysroot at once moves a directory...
+    let report = builder()
+        .build_from_source_with_report(fake.src_dir())
+        .unwrap();
+    assert_eq!(report.artifacts_installed, 3);
+    assert!(builder().verify().unwrap());
+    // ... while installing incrementally moves individual files (at least the hash file).
+    fs::remove_file(builder().target_dir().join("lib/libcore-fake.rlib")).unwrap();
+    builder()
+        .incremental_install(true)
+        .build_from_source(fake.src_dir())
+        .unwrap();
+    assert!(builder()
+        .target_dir()
+        .join("lib/libcore-fake.rlib")
+        .is_file());
+    assert!(builder().verify().unwrap());
+    // Nothing is left behind in the staging dir.
+    assert_eq!(fs::read_dir(staging_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+#[cfg(unix)]
+fn install_sources() {
+    let rustc_version = VersionMeta::for_command(Command::new("rustc")).unwrap();
+    let sysroot_dir = tempdir().unwrap();
+    // The sources are already where they would get installed, like in a toolchain sysroot.
+    let src_dir = sysroot_dir.path().join("lib/rustlib/src/rust/library");
+    fs::create_dir_all(&src_dir).unwrap();
+    for krate in ["core", "alloc", "std", "sysroot"] {
+        fs::create_dir(src_dir.join(krate)).unwrap();
+        fs::write(src_dir.join(krate).join("Cargo.toml"), "").unwrap();
+    }
+    SysrootBuilder::for_host(sysroot_dir.path(), rustc_version.clone())
+        .cargo(fake_cargo(FAKE_CARGO_BUILD))
+        .install_sources(true)
+        .build_from_source(&src_dir)
+        .unwrap();
+    assert!(src_dir.join("std/Cargo.toml").is_file());
+    // Installing sources from elsewhere replaces them.
+    let other_src_dir = fake_src_dir(&["core", "alloc", "std", "sysroot"]);
+    SysrootBuilder::for_host(sysroot_dir.path(), rustc_version)
+        .cargo(fake_cargo(FAKE_CARGO_BUILD))
+        .install_sources(true)
+        .build_from_source(other_src_dir.path())
+        .unwrap();
+    assert_eq!(
+        src_dir.canonicalize().unwrap(),
+        other_src_dir.path().canonicalize().unwrap()
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn incremental_install() {
+    use std::os::unix::fs::MetadataExt;
+    let fake = FakeSysroot::new();
+    let builder = || {
+        fake.fake_builder()
+            .incremental_install(true)
+            .force_rebuild(true)
+    };
+    builder().build_from_source(fake.src_dir()).unwrap();
+    let lib_dir = builder().target_dir().join("lib");
+    let libstd = lib_dir.join("libstd-fake.rlib");
+    let old_meta = fs::metadata(&libstd).unwrap();
+    // Something that is no longer part of the sysroot.
+    fs::write(lib_dir.join("libold-fake.rlib"), "").unwrap();
+    let report = builder()
+        .build_from_source_with_report(fake.src_dir())
+        .unwrap();
+    assert_eq!(report.artifacts_installed, 3);
+    // The unchanged libraries were left alone.
+    let new_meta = fs::metadata(&libstd).unwrap();
+    assert_eq!(new_meta.ino(), old_meta.ino());
+    assert_eq!(new_meta.modified().unwrap(), old_meta.modified().unwrap());
+    assert!(!lib_dir.join("libold-fake.rlib").exists());
+    assert!(builder().verify().unwrap());
+}
+
+#[test]
+#[cfg(unix)]
+fn doc_alongside_libs() {
+    const FAKE_CARGO_DOC: &str = r#"
+while [ $# -gt 0 ]; do
+    if [ "$1" = --target ]; then target=$2; fi
+    shift
+done
+case $CARGO_TARGET_DIR in /*) ;; *) CARGO_TARGET_DIR=$PWD/$CARGO_TARGET_DIR ;; esac
+mkdir -p "$CARGO_TARGET_DIR/$target/doc/std"
+touch "$CARGO_TARGET_DIR/$target/doc/std/index.html"
+"#;
+    let fake = FakeSysroot::new();
+    let src_dir = fake.src_dir();
+    let builder = |mode| fake.builder().build_mode(mode);
+    builder(BuildMode::Build)
+        .cargo(fake_cargo(FAKE_CARGO_BUILD))
+        .build_from_source(src_dir)
+        .unwrap();
+    let hash = builder(BuildMode::Build).installed_hash().unwrap();
+    builder(BuildMode::Doc)
+        .cargo(fake_cargo(FAKE_CARGO_DOC))
+        .build_from_source(src_dir)
+        .unwrap();
+    // The docs are installed next to the libraries, which are still up-to-date.
+    let target_dir = builder(BuildMode::Doc).target_dir();
+    assert!(target_dir.join("doc/std/index.html").is_file());
+    assert!(target_dir.join("lib/libstd-fake.rlib").is_file());
+    assert_eq!(builder(BuildMode::Build).installed_hash(), Some(hash));
+    assert!(!builder(BuildMode::Build).needs_rebuild(src_dir).unwrap());
+    assert!(!builder(BuildMode::Doc).needs_rebuild(src_dir).unwrap());
+    // Rebuilding the libraries keeps the docs.
+    builder(BuildMode::Build)
+        .cargo(fake_cargo(FAKE_CARGO_BUILD))
+        .force_rebuild(true)
+        .build_from_source(src_dir)
+        .unwrap();
+    assert!(target_dir.join("doc/std/index.html").is_file());
+    assert!(builder(BuildMode::Doc).verify().unwrap());
+}
+
+#[test]
+fn shadow_sysroot() {
+    let base = tempdir().unwrap();
+    let target_bin = base.path().join("lib/rustlib/x86_64-unknown-linux-gnu/bin");
+    fs::create_dir_all(base.path().join("bin")).unwrap();
+    fs::create_dir_all(&target_bin).unwrap();
+    fs::write(base.path().join("bin/rustc"), "rustc").unwrap();
+    fs::write(target_bin.join("rust-lld"), "lld").unwrap();
+    let dest = tempdir().unwrap();
+    let dest = dest.path().join("shadow");
+    create_shadow_sysroot(base.path(), &dest).unwrap();
+    assert_eq!(fs::read_to_string(dest.join("bin/rustc")).unwrap(), "rustc");
+    assert_eq!(
+        fs::read_to_string(dest.join("lib/rustlib/x86_64-unknown-linux-gnu/bin/rust-lld")).unwrap(),
+        "lld"
+    );
+    // Doing it again picks up changes in the base sysroot.
+    fs::remove_file(base.path().join("bin/rustc")).unwrap();
+    fs::write(base.path().join("bin/rustc"), "new rustc").unwrap();
+    create_shadow_sysroot(base.path(), &dest).unwrap();
+    assert_eq!(
+        fs::read_to_string(dest.join("bin/rustc")).unwrap(),
+        "new rustc"
+    );
+    assert!(create_shadow_sysroot(&dest.join("missing"), &dest).is_err());
+}
+
+#[test]
+#[cfg(unix)]
+fn shadow_sysroot_build() {
+    let rustc_version = VersionMeta::for_command(Command::new("rustc")).unwrap();
+    let src_dir = fake_src_dir(&["core", "alloc", "std", "sysroot"]);
+    let base = tempdir().unwrap();
+    let base_target_dir = base.path().join("lib/rustlib").join(&rustc_version.host);
+    fs::create_dir_all(base_target_dir.join("bin")).unwrap();
+    fs::create_dir_all(base_target_dir.join("lib/self-contained")).unwrap();
+    fs::write(base_target_dir.join("bin/rust-lld"), "lld").unwrap();
+    fs::write(base_target_dir.join("lib/self-contained/crt1.o"), "crt1").unwrap();
+    fs::write(base_target_dir.join("lib/libstd-toolchain.rlib"), "std").unwrap();
+    let sysroot_dir = tempdir().unwrap();
+    let sysroot_dir = sysroot_dir.path().join("shadow");
+    create_shadow_sysroot(base.path(), &sysroot_dir).unwrap();
+    for incremental_install in [false, true] {
+        let builder = || SysrootBuilder::for_host(&sysroot_dir, rustc_version.clone());
+        builder()
+            .cargo(fake_cargo(FAKE_CARGO_BUILD))
+            .incremental_install(incremental_install)
+            .force_rebuild(true)
+            .build_from_source(src_dir.path())
+            .unwrap();
+        // The toolchain's libraries got replaced, but everything else is still there.
+        let target_dir = builder().target_dir();
+        assert!(target_dir.join("lib/libstd-fake.rlib").is_file());
+        assert!(!target_dir.join("lib/libstd-toolchain.rlib").exists());
+        assert_eq!(
+            fs::read_to_string(target_dir.join("bin/rust-lld")).unwrap(),
+            "lld"
+        );
+        assert_eq!(
+            fs::read_to_string(target_dir.join("lib/self-contained/crt1.o")).unwrap(),
+            "crt1"
+        );
+        assert!(builder().verify().unwrap());
+    }
+    // The base sysroot is unaffected.
+    assert_eq!(
+        fs::read_to_string(base_target_dir.join("lib/libstd-toolchain.rlib")).unwrap(),
+        "std"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn cargo_stdout() {
+    let fake = FakeSysroot::new();
+    // We need cargo's stdout, even if the command says otherwise.
+    let mut cargo = fake_cargo(FAKE_CARGO_BUILD);
+    cargo.stdout(process::Stdio::inherit());
+    let report = fake
+        .builder()
+        .cargo(cargo)
+        .build_from_source_with_report(fake.src_dir())
+        .unwrap();
+    assert_eq!(report.status, SysrootStatus::SysrootBuilt);
+    assert_eq!(report.artifacts_installed, 3);
+    // A build that does not produce anything is an error.
+    let err = fake
+        .builder()
+        .cargo(fake_cargo("exit 0"))
+        .force_rebuild(true)
+        .build_from_source(fake.src_dir())
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("did not report any artifacts"));
+}
+
+#[test]
+#[cfg(unix)]
+fn cargo_failed() {
+    let fake = FakeSysroot::new();
+    let err = build_error(
+        fake.builder()
+            .cargo(fake_cargo("echo oops >&2; exit 1"))
+            .build_from_source(fake.src_dir()),
+    );
+    let BuildError::CargoFailed {
+        command, stderr, ..
+    } = &err
+    else {
+        panic!("unexpected error: {:?}", err);
+    };
+    assert_eq!(stderr, "oops\n");
+    assert!(command.contains("CARGO_ENCODED_RUSTFLAGS=$'"));
+    assert!(command.contains("sh -c 'echo oops >&2; exit 1' cargo build"));
+    assert!(command.contains(" --manifest-path "));
+}
+
+#[test]
+#[cfg(unix)]
+fn missing_lockfile() {
+    let fake = FakeSysroot::new();
+    for frozen in [false, true] {
+        let builder = || {
+            fake.builder()
+                .cargo(fake_cargo("exit 1"))
+                .locked(!frozen)
+                .frozen(frozen)
+        };
+        let err = build_error(builder().build_from_source(fake.src_dir()));
+        assert!(matches!(err, BuildError::MissingLockfile { .. }));
+        // With a lockfile, it is up to cargo.
+        fs::write(fake.src_dir().join("Cargo.lock"), "version = 3").unwrap();
+        let err = build_error(builder().build_from_source(fake.src_dir()));
+        assert!(matches!(err, BuildError::CargoFailed { .. }));
+        fs::remove_file(fake.src_dir().join("Cargo.lock")).unwrap();
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn lockfile() {
+    let rustc_version = VersionMeta::for_command(Command::new("rustc")).unwrap();
+    let root_dir = tempdir().unwrap();
+    let src_dir = root_dir.path().join("library");
+    for krate in ["core", "alloc", "std", "sysroot"] {
+        fs::create_dir_all(src_dir.join(krate)).unwrap();
+        fs::write(src_dir.join(krate).join("Cargo.toml"), "").unwrap();
+    }
+    // The old location of the lock file, outside of the library dir.
+    let lock_file_src = root_dir.path().join("Cargo.lock");
+    let sysroot_dir = tempdir().unwrap();
+    let build_dir = tempdir().unwrap();
+    let lock_file = build_dir.path().join("Cargo.lock");
+    let builder = || SysrootBuilder::for_host(sysroot_dir.path(), rustc_version.clone());
+    let build = || {
+        let cargo = fake_cargo("exit 1");
+        builder()
+            .cargo(cargo)
+            .build_dir(build_dir.path())
+            .build_from_source(&src_dir)
+            .unwrap_err();
+    };
+
+    let hash = builder().current_hash(&src_dir).unwrap();
+    fs::write(&lock_file_src, "version = 3").unwrap();
+    let lock_hash = builder().current_hash(&src_dir).unwrap();
+    assert_ne!(hash, lock_hash);
+
+    build();
+    assert_eq!(fs::read_to_string(&lock_file).unwrap(), "version = 3");
+    // As long as the source lock file does not change, the resolved one is kept.
+    fs::write(&lock_file, "version = 3 # resolved").unwrap();
+    build();
+    assert_eq!(
+        fs::read_to_string(&lock_file).unwrap(),
+        "version = 3 # resolved"
+    );
+    fs::write(&lock_file_src, "version = 4").unwrap();
+    assert_ne!(builder().current_hash(&src_dir).unwrap(), lock_hash);
+    build();
+    assert_eq!(fs::read_to_string(&lock_file).unwrap(), "version = 4");
+}
+
+#[test]
+fn manifest() {
+    let src_dir = fake_src_dir(&[
+        "core",
+        "alloc",
+        "std",
+        "sysroot",
+        "rustc-std-workspace-core",
+    ]);
+    let sysroot_dir = tempdir().unwrap();
+    let src = src_dir.path().canonicalize().unwrap();
+    let builder = || SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf");
+
+    let manifest = builder()
+        .sysroot_config(SysrootConfig::CoreOnly)
+        .opt_level("3")
+        .manifest(src_dir.path())
+        .unwrap();
+    assert!(manifest.contains("opt-level = 3\n"));
+    assert!(manifest.contains(&format!("path = {:?}", src.join("core"))));
+    assert!(!manifest.contains("[dependencies.alloc]"));
+    assert!(!manifest.contains("[dependencies.std]"));
+    assert!(manifest.contains("[patch.crates-io.rustc-std-workspace-core]"));
+
+    let manifest = builder()
+        .sysroot_config(SysrootConfig::WithStd {
+            std_features: vec!["backtrace".to_owned()],
+        })
+        .panic_strategy(PanicStrategy::Abort)
+        .lto(LtoMode::Fat)
+        .codegen_units(1)
+        .debuginfo(DebugInfo::LineTablesOnly)
+        .opt_level("z")
+        .overflow_checks(true)
+        .profiler(true)
+        .manifest(src_dir.path())
+        .unwrap();
+    assert!(manifest.contains("[dependencies.profiler_builtins]"));
+    assert!(manifest.contains(r#"opt-level = "z""#));
+    assert!(manifest.contains("overflow-checks = true"));
+    assert!(manifest.contains("[dependencies.std]"));
+    assert!(manifest.contains(r#"features = ["backtrace"]"#));
+    assert!(manifest.contains("[dependencies.sysroot]"));
+    assert!(manifest.contains("default-features = false"));
+    assert!(manifest.contains("panic = 'abort'"));
+    assert!(manifest.contains(r#"lto = "fat""#));
+    assert!(manifest.contains("codegen-units = 1\n"));
+    assert!(manifest.contains(r#"debug = "line-tables-only""#));
+    // Patches are only generated for workspace crates that exist.
+    assert!(manifest.contains("[patch.crates-io.rustc-std-workspace-core]"));
+    assert!(!manifest.contains("[patch.crates-io.rustc-std-workspace-std]"));
+
+    let manifest = builder()
+        .sysroot_config(SysrootConfig::WithStd {
+            std_features: vec!["backtrace".to_owned()],
+        })
+        .include_test(false)
+        .manifest(src_dir.path())
+        .unwrap();
+    assert!(manifest.contains(r#"features = ["backtrace", "panic-unwind"]"#));
+    assert!(!manifest.contains("[dependencies.sysroot]"));
+    assert!(!manifest.contains("[dependencies.test]"));
+
+    // Check builds do not need optimizations, but should keep the `cfg`s of a release build.
+    let manifest = builder()
+        .build_mode(BuildMode::Check)
+        .manifest(src_dir.path())
+        .unwrap();
+    assert!(manifest.contains(r#"inherits = "dev""#));
+    assert!(manifest.contains("debug-assertions = false\n"));
+    assert!(manifest.contains("overflow-checks = false\n"));
+    let manifest = builder()
+        .build_mode(BuildMode::Check)
+        .profile(SysrootProfile::Release)
+        .manifest(src_dir.path())
+        .unwrap();
+    assert!(manifest.contains(r#"inherits = "release""#));
+    assert!(!manifest.contains("debug-assertions"));
+
+    let manifest = builder()
+        .manifest_hook(|_, manifest| manifest.push_str("\n[workspace]\n"))
+        .manifest(src_dir.path())
+        .unwrap();
+    assert!(manifest.ends_with("\n[workspace]\n"));
+}
+
+#[test]
+fn manifest_panic_abort() {
+    let sysroot_dir = tempdir().unwrap();
+    // Newer sources build `sysroot`, older ones `test`; both enable `panic-unwind` by default.
+    for main_crate in ["sysroot", "test"].iter() {
+        let src_dir = fake_src_dir(&["core", "alloc", "std", main_crate]);
+        fs::write(
+            src_dir.path().join(main_crate).join("Cargo.toml"),
+            r#"
+[package]
+name = "main"
+
+[features]
+default = ["std_detect_file_io", "std_detect_dlsym_getauxval", "panic-unwind"]
+panic-unwind = ["std/panic-unwind"]
+"#,
+        )
+        .unwrap();
+        let dependency = |panic_strategy| {
+            let manifest = SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+                .panic_strategy(panic_strategy)
+                .manifest(src_dir.path())
+                .unwrap();
+            let manifest: toml::Table = manifest.parse().unwrap();
+            manifest["dependencies"][*main_crate]
+                .as_table()
+                .unwrap()
+                .clone()
+        };
+
+        let unwind = dependency(PanicStrategy::Unwind);
+        assert!(!unwind.contains_key("default-features"));
+        assert!(!unwind.contains_key("features"));
+        // The other default features are enabled again, only `panic-unwind` is gone.
+        let abort = dependency(PanicStrategy::Abort);
+        assert_eq!(abort["default-features"].as_bool(), Some(false));
+        assert_eq!(
+            abort["features"].as_array().unwrap(),
+            &["std_detect_file_io", "std_detect_dlsym_getauxval"]
+                .iter()
+                .map(|&feature| toml::Value::from(feature))
+                .collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn manifest_old_panic_unwind() {
+    let src_dir = fake_src_dir(&["core", "alloc", "std", "test"]);
+    // Older sources spell the feature with an underscore.
+    fs::write(
+        src_dir.path().join("std").join("Cargo.toml"),
+        "[features]\npanic_unwind = [\"panic_unwind\"]\n",
+    )
+    .unwrap();
+    fs::write(
+        src_dir.path().join("test").join("Cargo.toml"),
+        "[features]\ndefault = [\"std_detect_file_io\", \"panic_unwind\"]\npanic_unwind = [\"std/panic_unwind\"]\n",
+    )
+    .unwrap();
+    let sysroot_dir = tempdir().unwrap();
+    let builder = || SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf");
+
+    let manifest = builder()
+        .include_test(false)
+        .manifest(src_dir.path())
+        .unwrap();
+    assert!(manifest.contains(r#"features = ["panic_unwind"]"#));
+    let manifest = builder()
+        .panic_strategy(PanicStrategy::Abort)
+        .manifest(src_dir.path())
+        .unwrap();
+    assert!(manifest.contains(r#"features = ["std_detect_file_io"]"#));
+}
+
+#[test]
+fn zero_codegen_units() {
+    let fake = FakeSysroot::new();
+    let err = build_error(
+        fake.builder()
+            .codegen_units(0)
+            .build_from_source(fake.src_dir()),
+    );
+    assert!(matches!(
+        err,
+        BuildError::InvalidSetting {
+            setting: "codegen_units",
+            ..
+        }
+    ));
+}
+
+#[test]
+fn invalid_cargo_config() {
+    let fake = FakeSysroot::new();
+    for key in ["", "net.retry=5", "net retry"] {
+        let builder = || fake.builder().cargo_config([(key, "5")]);
+        let err = build_error(builder().build_from_source(fake.src_dir()));
+        assert!(matches!(
+            err,
+            BuildError::InvalidSetting {
+                setting: "cargo_config",
+                ..
+            }
+        ));
+        assert!(builder().dry_run(fake.src_dir()).is_err());
+    }
+}
+
+#[test]
+fn manifest_escaping() {
+    let tmp_dir = tempdir().unwrap();
+    // Backslashes and quotes are not allowed in Windows paths.
+    let name = if cfg!(windows) {
+        "with space"
+    } else {
+        r#"with space\and "quotes""#
+    };
+    let src_dir = tmp_dir.path().join(name);
+    for krate in ["core", "alloc", "std", "sysroot"].iter() {
+        fs::create_dir_all(src_dir.join(krate)).unwrap();
+        fs::write(src_dir.join(krate).join("Cargo.toml"), "").unwrap();
+    }
+    let sysroot_dir = tempdir().unwrap();
+    let manifest = SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+        .sysroot_config(SysrootConfig::WithStd {
+            std_features: vec![r#"a"b"#.to_owned()],
+        })
+        .manifest(&src_dir)
+        .unwrap();
+    let manifest: toml::Table = manifest.parse().unwrap();
+    let std = &manifest["dependencies"]["std"];
+    assert_eq!(
+        Path::new(std["path"].as_str().unwrap()),
+        src_dir.canonicalize().unwrap().join("std")
+    );
+    assert_eq!(std["features"][0].as_str(), Some(r#"a"b"#));
+}
+
+#[test]
+fn manifest_patches() {
+    let src_dir = fake_src_dir(&[
+        "core",
+        "alloc",
+        "std",
+        "sysroot",
+        "rustc-std-workspace-core",
+        "rustc-std-workspace-alloc",
+        "rustc-std-workspace-std",
+    ]);
+    let sysroot_dir = tempdir().unwrap();
+    let patches = |config| {
+        let manifest = SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+            .sysroot_config(config)
+            .manifest(src_dir.path())
+            .unwrap();
+        ["core", "alloc", "std"]
+            .iter()
+            .copied()
+            .filter(|krate| {
+                manifest.contains(&format!("[patch.crates-io.rustc-std-workspace-{krate}]"))
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(patches(SysrootConfig::CoreOnly), ["core"]);
+    // Only `std` depends on `rustc-std-workspace-alloc`, so patching it would produce an
+    // "unused patch" warning.
+    assert_eq!(patches(SysrootConfig::NoStd), ["core"]);
+    assert_eq!(
+        patches(SysrootConfig::WithStd {
+            std_features: vec![]
+        }),
+        ["core", "alloc", "std"]
+    );
+}
+
+#[test]
+fn for_host() {
+    let rustc_version = VersionMeta::for_command(Command::new("rustc")).unwrap();
+    let sysroot_dir = tempdir().unwrap();
+    let builder = SysrootBuilder::for_host(sysroot_dir.path(), rustc_version.clone());
+    assert_eq!(builder.target(), OsStr::new(&rustc_version.host));
+}
+
+#[test]
+fn clean() {
+    let sysroot_dir = tempdir().unwrap();
+    let builder = SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf");
+    // Nothing to clean yet.
+    builder.clean().unwrap();
+
+    let lib_dir = builder.target_dir().join("lib");
+    fs::create_dir_all(&lib_dir).unwrap();
+    fs::write(lib_dir.join("libcore.rlib"), "").unwrap();
+    builder.clean().unwrap();
+    assert!(!builder.target_dir().exists());
+    assert!(sysroot_dir.path().exists());
+
+    builder.clean_all().unwrap();
+    assert!(!sysroot_dir.path().exists());
+}
+
+#[test]
+fn installed_targets() {
+    let sysroot_dir = tempdir().unwrap();
+    let builder = SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf");
+    assert!(builder.installed_targets().unwrap().is_empty());
+
+    let rustlib_dir = sysroot_dir.path().join("lib").join("rustlib");
+    for target in ["x86_64-unknown-linux-gnu", "thumbv7em-none-eabihf"] {
+        fs::create_dir_all(rustlib_dir.join(target)).unwrap();
+        fs::write(
+            rustlib_dir.join(target).join(".rustc-build-sysroot-hash"),
+            "0",
+        )
+        .unwrap();
+    }
+    // Not built by us.
+    fs::create_dir_all(rustlib_dir.join("src")).unwrap();
+    assert_eq!(
+        builder.installed_targets().unwrap(),
+        ["thumbv7em-none-eabihf", "x86_64-unknown-linux-gnu"]
+    );
+}
+
+#[test]
+fn verify() {
+    let sysroot_dir = tempdir().unwrap();
+    let builder = || {
+        SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+            .sysroot_config(SysrootConfig::NoStd)
+    };
+    assert!(!builder().verify().unwrap());
+
+    let target_dir = builder().target_dir();
+    fs::create_dir_all(target_dir.join("lib")).unwrap();
+    fs::write(target_dir.join(".rustc-build-sysroot-hash"), "0").unwrap();
+    assert!(!builder().verify().unwrap());
+    let rlib = target_dir
+        .join("lib")
+        .join("liballoc-0123456789abcdef.rlib");
+    fs::write(&rlib, "").unwrap();
+    assert!(builder().verify().unwrap());
+    // Installing extra files does not change what the libraries look like.
+    assert!(builder()
+        .artifact_extensions(["a", "dwp"])
+        .verify()
+        .unwrap());
+    // A sysroot with std needs more than that.
+    assert!(!builder()
+        .sysroot_config(SysrootConfig::WithStd {
+            std_features: vec![]
+        })
+        .verify()
+        .unwrap());
+    fs::remove_file(&rlib).unwrap();
+    assert!(!builder().verify().unwrap());
+}
+
+#[test]
+fn rebuild_reason() {
+    let fake = FakeSysroot::new();
+    let src_dir = fake.src_dir();
+    assert_eq!(
+        fake.builder().rebuild_reason(src_dir).unwrap(),
+        Some(RebuildReason::NotInstalled)
+    );
+
+    let hash_file = fake
+        .builder()
+        .target_dir()
+        .join(".rustc-build-sysroot-hash");
+    fs::create_dir_all(hash_file.parent().unwrap()).unwrap();
+    // Old hash files do not record any inputs.
+    fs::write(&hash_file, "0").unwrap();
+    assert_eq!(
+        fake.builder().rebuild_reason(src_dir).unwrap(),
+        Some(RebuildReason::Unknown)
+    );
+    assert_eq!(
+        fake.builder()
+            .force_rebuild(true)
+            .rebuild_reason(src_dir)
+            .unwrap(),
+        Some(RebuildReason::ForceRebuild)
+    );
+
+    fs::write(&hash_file, r#"{"hash": 0, "mode": "check"}"#).unwrap();
+    let Some(RebuildReason::InputsChanged { inputs }) =
+        fake.builder().rebuild_reason(src_dir).unwrap()
+    else {
+        panic!("expected changed inputs");
+    };
+    assert!(inputs.iter().any(|input| input == "mode"));
+}
+
+#[test]
+fn link_target_dir() {
+    let canonical_dir = tempdir().unwrap();
+    let canonical = SysrootBuilder::new(canonical_dir.path(), "thumbv7em-none-eabihf");
+    let canonical_target_dir = canonical.target_dir();
+    fs::create_dir_all(canonical_target_dir.join("lib")).unwrap();
+    fs::write(
+        canonical_target_dir
+            .join("lib")
+            .join("libcore-0123456789abcdef.rlib"),
+        "",
+    )
+    .unwrap();
+    fs::write(canonical_target_dir.join(".rustc-build-sysroot-hash"), "42").unwrap();
+
+    let sysroot_dir = tempdir().unwrap();
+    let builder = SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+        .sysroot_config(SysrootConfig::CoreOnly);
+    // Replaces whatever was there before.
+    fs::create_dir_all(builder.target_dir().join("lib")).unwrap();
+    builder.link_target_dir(&canonical_target_dir).unwrap();
+    assert_eq!(builder.installed_hash(), Some(42));
+    assert!(builder.verify().unwrap());
+    // Linking again is fine, too.
+    builder.link_target_dir(&canonical_target_dir).unwrap();
+    assert_eq!(builder.installed_hash(), Some(42));
+
+    // Cleaning up the linked sysroot leaves the original alone.
+    builder.clean().unwrap();
+    assert_eq!(builder.installed_hash(), None);
+    assert_eq!(canonical.installed_hash(), Some(42));
+
+    assert!(builder
+        .link_target_dir(&canonical_dir.path().join("missing"))
+        .is_err());
+}
+
+#[test]
+fn variants() {
+    let fake = FakeSysroot::new();
+    let src_dir = fake.src_dir();
+    let variant_dir = fake.sysroot_dir().join("variants").join("backtrace");
+    let variant = fake.builder().variant("backtrace");
+    assert_eq!(variant.sysroot_dir(), variant_dir);
+    assert!(variant.target_dir().starts_with(&variant_dir));
+    assert_eq!(
+        variant.rustc_args(),
+        [OsStr::new("--sysroot"), variant_dir.as_os_str()]
+    );
+    assert_ne!(
+        variant.current_hash(src_dir).unwrap(),
+        fake.builder().current_hash(src_dir).unwrap()
+    );
+    // Setting the variant again does not nest.
+    assert_eq!(
+        fake.builder().variant("a").variant("b").sysroot_dir(),
+        fake.sysroot_dir().join("variants").join("b")
+    );
+}
+
+#[test]
+fn invalid_variant() {
+    let fake = FakeSysroot::new();
+    let marker = fake.sysroot_dir().join("marker");
+    fs::write(&marker, "").unwrap();
+    for variant in ["", "..", "a/b"] {
+        let builder = || fake.builder().variant(variant);
+        let err = build_error(builder().build_from_source(fake.src_dir()));
+        assert!(matches!(
+            err,
+            BuildError::InvalidSetting {
+                setting: "variant",
+                ..
+            }
+        ));
+        // Cleaning up must not touch whatever the name points at.
+        assert!(builder().clean_all().is_err());
+        assert!(marker.exists());
+    }
+}
+
+#[test]
+fn compatible_rustc() {
+    let fake = FakeSysroot::new();
+    let src_dir = fake.src_dir();
+    let rustc_version = &fake.rustc_version;
+    let builder = fake.builder();
+    // Pretend we built a sysroot.
+    let lib_dir = builder.target_dir().join("lib");
+    fs::create_dir_all(&lib_dir).unwrap();
+    fs::write(lib_dir.join("libstd-0123456789abcdef.rlib"), "").unwrap();
+    let hash = builder.current_hash(src_dir).unwrap();
+    fs::write(
+        builder.target_dir().join(".rustc-build-sysroot-hash"),
+        hash.to_string(),
+    )
+    .unwrap();
+
+    assert!(builder.is_compatible_with(rustc_version, src_dir).unwrap());
+    let mut other_version = rustc_version.clone();
+    other_version.commit_hash = Some("0000000000000000000000000000000000000000".to_owned());
+    assert!(!builder.is_compatible_with(&other_version, src_dir).unwrap());
+}
+
+#[test]
+fn std_on_no_std_target() {
+    // A fake source dir is enough, since we should fail before invoking cargo.
+    let fake = FakeSysroot::new();
+    for target in [
+        "thumbv7em-none-eabihf",
+        "riscv32imac-unknown-none-elf",
+        "nvptx64-nvidia-cuda",
+    ] {
+        let err = build_error(
+            SysrootBuilder::new(fake.sysroot_dir(), target).build_from_source(fake.src_dir()),
+        );
+        assert!(matches!(err, BuildError::StdNotSupported { .. }));
+    }
+}
+
+#[test]
+fn dry_run() {
+    let src_dir = fake_src_dir(&["std"]);
+    let sysroot_dir = tempdir().unwrap();
+    let sysroot_dir = sysroot_dir.path().join("sysroot");
+    let dry_run = SysrootBuilder::new(&sysroot_dir, "thumbv7em-none-eabihf")
+        .build_mode(BuildMode::Check)
+        .sysroot_config(SysrootConfig::NoStd)
+        .offline(true)
+        .cargo_arg("--config=net.retry=5")
+        .cargo_config([("net.git-fetch-with-cli", "true"), ("build.jobs", "2")])
+        .verbosity(Verbosity::Verbose)
+        .rustc_wrapper("sccache")
+        .env("CC", "clang")
+        .std_rustflags(["-Zmacro-backtrace"])
+        .dry_run(src_dir.path())
+        .unwrap();
+    assert!(dry_run.needs_rebuild);
+    assert_eq!(dry_run.args[0], "check");
+    assert!(dry_run.args.iter().any(|arg| arg == "-v"));
+    assert_eq!(dry_run.args.last().unwrap(), "--config=net.retry=5");
+    let config_args = &dry_run.args[dry_run.args.len() - 5..dry_run.args.len() - 1];
+    assert_eq!(
+        config_args,
+        [
+            "--config",
+            "net.git-fetch-with-cli=true",
+            "--config",
+            "build.jobs=2"
+        ]
+    );
+    assert!(dry_run.args.iter().any(|arg| arg == "--offline"));
+    assert!(dry_run
+        .envs
+        .iter()
+        .any(|(key, value)| key == "CARGO_ENCODED_RUSTFLAGS"
+            && value
+                .as_deref()
+                .unwrap()
+                .to_string_lossy()
+                .ends_with("\x1f-Zmacro-backtrace")));
+    assert!(dry_run
+        .envs
+        .iter()
+        .any(|(key, value)| key == "RUSTC_WRAPPER"
+            && value.as_deref() == Some(OsStr::new("sccache"))));
+    assert!(dry_run
+        .envs
+        .iter()
+        .any(|(key, value)| key == "CC" && value.as_deref() == Some(OsStr::new("clang"))));
+    // Nothing was created.
+    assert!(!sysroot_dir.exists());
+}
+
+#[test]
+fn codegen_rustflags() {
+    let src_dir = fake_src_dir(&["core", "alloc", "std"]);
+    let sysroot_dir = tempdir().unwrap();
+    let dry_run = SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+        .sysroot_config(SysrootConfig::NoStd)
+        .rustflag("-Ctarget-cpu=cortex-m4")
+        .target_cpu("cortex-m4")
+        .target_features(["dsp", "-fp64"])
+        .force_frame_pointers(true)
+        .dry_run(src_dir.path())
+        .unwrap();
+    let (_, rustflags) = dry_run
+        .envs
+        .iter()
+        .find(|(key, _)| key == "CARGO_ENCODED_RUSTFLAGS")
+        .unwrap();
+    let rustflags = rustflags.as_deref().unwrap().to_str().unwrap();
+    let rustflags: Vec<_> = rustflags.split('\x1f').collect();
+    assert_eq!(
+        rustflags
+            .iter()
+            .filter(|flag| **flag == "-Ctarget-cpu=cortex-m4")
+            .count(),
+        1
+    );
+    assert!(rustflags.contains(&"-Ctarget-feature=+dsp,-fp64"));
+    assert!(rustflags.contains(&"-Cforce-frame-pointers=yes"));
+}
+
+#[test]
+fn lib_metadata() {
+    let src_dir = fake_src_dir(&["core", "alloc", "std"]);
+    let sysroot_dir = tempdir().unwrap();
+    let builder = || {
+        SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf")
+            .sysroot_config(SysrootConfig::NoStd)
+    };
+    let lib_metadata = |builder: SysrootBuilder| {
+        let dry_run = builder.dry_run(src_dir.path()).unwrap();
+        let (_, lib_metadata) = dry_run
+            .envs
+            .into_iter()
+            .find(|(key, _)| key == "__CARGO_DEFAULT_LIB_METADATA")
+            .unwrap();
+        lib_metadata.unwrap()
+    };
+    let default = lib_metadata(builder());
+    assert!(default
+        .to_str()
+        .unwrap()
+        .starts_with("rustc-build-sysroot-"));
+    assert_eq!(lib_metadata(builder()), default);
+    // Different configurations get different metadata.
+    assert_ne!(lib_metadata(builder().rustflag("-Copt-level=1")), default);
+    assert_ne!(
+        lib_metadata(builder().panic_strategy(PanicStrategy::Abort)),
+        default
+    );
+    // Explicit metadata is used as-is.
+    assert_eq!(lib_metadata(builder().lib_metadata("my-tool")), "my-tool");
+}
+
+#[test]
+fn dry_run_toolchain() {
+    let fake = FakeSysroot::new();
+    let dry_run = fake
+        .builder()
+        .toolchain("nightly-2024-06-01")
+        .build_mode(BuildMode::Check)
+        .dry_run(fake.src_dir())
+        .unwrap();
+    // The toolchain has to come before the subcommand.
+    assert_eq!(dry_run.args[0], "+nightly-2024-06-01");
+    assert_eq!(dry_run.args[1], "check");
+}
+
+#[test]
+fn rustc_args() {
+    let sysroot_dir = tempdir().unwrap();
+    let builder = SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf");
+    assert_eq!(
+        builder.rustc_args(),
+        [OsStr::new("--sysroot"), sysroot_dir.path().as_os_str()]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn needs_rebuild() {
+    let fake = FakeSysroot::new();
+    let src_dir = fake.src_dir();
+    assert!(fake.fake_builder().needs_rebuild(src_dir).unwrap());
+    fake.fake_builder().build_from_source(src_dir).unwrap();
+    assert!(!fake.fake_builder().needs_rebuild(src_dir).unwrap());
+}
+
+#[test]
+#[cfg(unix)]
+fn on_event() {
+    let fake = FakeSysroot::new();
+    let phases = |builder: SysrootBuilder| {
+        let mut phases = vec![];
+        builder
+            .on_event(|event| phases.push(event.phase))
+            .build_from_source(fake.src_dir())
+            .unwrap();
+        phases
+    };
+    assert_eq!(
+        phases(fake.fake_builder()),
+        [
+            BuildPhase::CargoStarted,
+            BuildPhase::CargoFinished,
+            BuildPhase::Staging,
+            BuildPhase::Installed
+        ]
+    );
+    assert_eq!(phases(fake.fake_builder()), [BuildPhase::AlreadyCached]);
+}
+
+#[test]
+#[cfg(unix)]
+fn installed_hash() {
+    let fake = FakeSysroot::new();
+    let src_dir = fake.src_dir();
+    assert_eq!(fake.fake_builder().installed_hash(), None);
+    fake.fake_builder().build_from_source(src_dir).unwrap();
+    assert_eq!(
+        fake.fake_builder().installed_hash(),
+        Some(fake.fake_builder().current_hash(src_dir).unwrap())
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn build_report() {
+    let fake = FakeSysroot::new();
+    let report = || {
+        fake.fake_builder()
+            .build_from_source_with_report(fake.src_dir())
+            .unwrap()
+    };
+    let built = report();
+    assert_eq!(built.status, SysrootStatus::SysrootBuilt);
+    assert_eq!(built.artifacts_installed, 3);
+    let cached = report();
+    assert_eq!(cached.status, SysrootStatus::AlreadyCached);
+    assert_eq!(cached.artifacts_installed, 0);
+}
+
+#[test]
+fn rustc_flags() {
+    let sysroot_dir = tempdir().unwrap();
+    let builder = SysrootBuilder::new(sysroot_dir.path(), "thumbv7em-none-eabihf");
+    let lib_dir = sysroot_dir
+        .path()
+        .join("lib")
+        .join("rustlib")
+        .join("thumbv7em-none-eabihf")
+        .join("lib");
+    assert_eq!(builder.rustc_flags()[..2], builder.rustc_args());
+    assert_eq!(
+        builder.rustc_flags()[2..],
+        [
+            OsString::from("-L"),
+            OsString::from(format!("dependency={}", lib_dir.display()))
+        ]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn verify_built() {
+    let fake = FakeSysroot::new();
+    assert!(!fake.fake_builder().verify().unwrap());
+    fake.fake_builder()
+        .build_from_source(fake.src_dir())
+        .unwrap();
+    assert!(fake.fake_builder().verify().unwrap());
+}
+
+#[test]
+#[cfg(unix)]
+fn without_hash_file() {
+    let fake = FakeSysroot::new();
+    let src_dir = fake.src_dir();
+    fake.fake_builder()
+        .write_hash(false)
+        .build_from_source(src_dir)
+        .unwrap();
+    assert!(fake
+        .fake_builder()
+        .target_dir()
+        .join("lib/libstd-fake.rlib")
+        .is_file());
+    // Without a hash file, the sysroot is always rebuilt.
+    assert_eq!(fake.fake_builder().installed_hash(), None);
+    assert!(fake.fake_builder().needs_rebuild(src_dir).unwrap());
+}